@@ -0,0 +1,483 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::num::NonZeroU32;
+use std::time::Duration;
+use clap::{Parser, Subcommand, ValueEnum};
+use glob::Pattern;
+use ipnet::IpNet;
+use regex::Regex;
+use geoping::{Compression, CountryCorrection, Fallback, IpVersion, OutputFormat, OutputStyle, ServerMetric};
+use geoping::progress::Verbosity;
+
+pub const DEFAULT_PING_COUNT: u16 = 10;// per IP
+pub const MAX_PAYLOAD_BYTES: u16 = 65500;// leaves room for the IP and ICMP headers
+pub const DEFAULT_TIMEOUT_MS: u64 = 500;// ms
+pub const DEFAULT_CONCURRENCY: u16 = 64;// servers pinged at once within a country
+pub const DEFAULT_COUNTRY_CONCURRENCY: u16 = 1;// countries pinged at once
+pub const DEFAULT_IPINFO_CONCURRENCY: u16 = 8;// IpInfo lookups at once
+pub const DEFAULT_IPINFO_RETRIES: u32 = 3;
+pub const DEFAULT_IPINFO_RETRY_DELAY_MS: u64 = 500;// ms, doubled on every retry
+pub const DEFAULT_IPINFO_RATE_LIMIT_PAUSE_SECS: u64 = 60;
+pub const DEFAULT_IPINFO_MAX_WAIT_SECS: u64 = 900;// all rate limit pauses together
+pub const DEFAULT_MIN_SAMPLES: u32 = 3;// servers per country
+pub const DEFAULT_PRECISION: u8 = 3;// decimals of the CSV numbers
+pub const DEFAULT_LOSS_PENALTY: f64 = 1.0;// score = median * (1 + loss fraction * penalty)
+pub const DEFAULT_OUTPUT_STEM: &str = "rtt_result";// extension follows the format
+pub const DEFAULT_MERGED_OUTPUT: &str = "rtt_merged.csv";
+pub const DEFAULT_IPINFO_CACHE: &str = "ipinfo_cache.json";
+pub const DEFAULT_PARTIAL: &str = "rtt_partial.jsonl";
+
+fn existing_dir(value: &str) -> Result<PathBuf, String>
+{
+    let path = PathBuf::from(value);
+
+    if !path.exists() { return Err(format!("{} does not exist", value)); }
+    if !path.is_dir() { return Err(format!("{} is not a directory", value)); }
+
+    Ok(path)
+}
+
+/// Finite and not negative, a NaN score would leave countries unsortable
+fn loss_penalty(value: &str) -> Result<f64, String>
+{
+    let penalty: f64 = value.trim().parse().map_err(|_| format!("{} is not a number", value))?;
+
+    if !penalty.is_finite() || penalty < 0.0 { return Err(format!("{} is not a weight of 0 or more", value)); }
+
+    Ok(penalty)
+}
+
+/// Address of one of this host's interfaces, anything else can't be bound
+fn local_addr(value: &str) -> Result<IpAddr, String>
+{
+    let addr: IpAddr = value.trim().parse().map_err(|_| format!("{} is not an IP address", value))?;
+
+    UdpSocket::bind(SocketAddr::new(addr, 0)).map_err(|err| format!("{} is not an address of this host: {}", value, err))?;
+
+    Ok(addr)
+}
+
+/// Seconds, or a number followed by s, m or h
+fn interval(value: &str) -> Result<Duration, String>
+{
+    let (number, unit) = match value.trim().strip_suffix(['s', 'm', 'h'])
+    {
+        Some(number) => (number, value.trim().chars().last().unwrap()),
+        None => (value.trim(), 's'),
+    };
+
+    let number: u64 = number.parse().map_err(|_| format!("{} is not a duration like 90, 30s, 15m or 1h", value))?;
+    if number == 0 { return Err("the interval must be longer than 0".to_string()); }
+
+    let secs = match unit
+    {
+        'h' => number.checked_mul(3600),
+        'm' => number.checked_mul(60),
+        _ => Some(number),
+    };
+    let secs = secs.ok_or("interval too large")?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeoProviderKind
+{
+    /// ipinfo.io, needs GEOPING_IPINFO_TOKEN
+    Ipinfo,
+    /// Local GeoLite2 / GeoIP2 City database given with --mmdb
+    Maxmind,
+}
+
+/// Where locations are looked up when the geo provider can't place a server or can't be used at all
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeoFallback
+{
+    /// ip-api.com, free and without a token, but rate limited and it gets every IP looked up
+    Free,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command
+{
+    /// Combine CSV outputs of runs from several vantage points, keeping the fastest one of every country.
+    /// They have to be written by the same version with the same --locale
+    Merge
+    {
+        /// CSV outputs to combine, the name of each one tells its vantage point
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+    /// Check the server files for duplicate IPs, private or reserved addresses, servers without a city,
+    /// malformed IPs and files not named after a country code, without pinging anything.
+    /// Exits with 1 if there are any, so it can gate inventory changes in CI
+    Lint
+    {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Pings DNS servers from public-dns.info JSON files and outputs RTT statistics per country")]
+pub struct Args
+{
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Ping every IP once even if it's listed under a few cities or countries, all of them get the result
+    #[arg(long)]
+    pub dedupe: bool,
+
+    /// Directories with the server JSON files, repeated or comma separated, the current directory if not given.
+    /// Countries listed in more than one get the servers of all of them
+    #[arg(long = "input-dir", value_name = "PATH", value_parser = existing_dir, value_delimiter = ',', global = true)]
+    pub input_dirs: Vec<PathBuf>,
+
+    /// Only load the server files whose path within --input-dir matches this pattern, e.g. "??.json"
+    /// or "servers/*.json", to leave out other JSON kept next to them. `*` doesn't cross directories, `**/` does
+    #[arg(long, value_name = "PATTERN", conflicts_with = "combined", global = true)]
+    pub glob: Option<Pattern>,
+
+    /// Single JSON file with the servers of every country, e.g. {"DE": [...], "PL": {...}}, instead of --input-dir
+    #[arg(long, value_name = "PATH", conflicts_with = "input_dirs", global = true)]
+    pub combined: Option<PathBuf>,
+
+    /// Field of every JSON server object holding its IP, e.g. "address"
+    #[arg(long = "ip-field", value_name = "NAME", default_value = "ip", global = true)]
+    pub ip_field: String,
+
+    /// Field of every JSON server object holding its city, e.g. "location"
+    #[arg(long = "city-field", value_name = "NAME", default_value = "city", global = true)]
+    pub city_field: String,
+
+    /// Abort if any server file or entry had to be skipped instead of pinging the rest
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Only ping these countries, e.g. DE,PL,FR
+    #[arg(long = "country", visible_alias = "only-country", value_name = "CODES", value_delimiter = ',')]
+    pub countries: Vec<String>,
+
+    /// Print every server of this country after pinging, with all of its replies, including servers left out of the statistics
+    #[arg(long = "debug-country", value_name = "CODE")]
+    pub debug_country: Option<String>,
+
+    /// Only ping servers whose city matches this regular expression, e.g. "^Frankfurt" or "(?i)frankfurt|berlin"
+    #[arg(long = "city-filter", value_name = "REGEX")]
+    pub city_filter: Option<Regex>,
+
+    /// Ping at most N servers of every country, the first ones listed unless --sample-seed is given
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub sample: Option<u32>,
+
+    /// Pick the --sample servers randomly, the same seed picks the same servers
+    #[arg(long = "sample-seed", value_name = "SEED", requires = "sample")]
+    pub sample_seed: Option<u64>,
+
+    /// Ping the countries and their servers in random order, which spreads the load on neighbouring
+    /// subnets. Results don't depend on the order, only when each server is pinged does. The same seed
+    /// gives the same order, without one a random seed is logged
+    #[arg(long, value_name = "SEED", num_args = 0..=1)]
+    pub shuffle: Option<Option<u64>>,
+
+    /// Only ping IPv4 or IPv6 addresses, e.g. on hosts without IPv6 connectivity
+    #[arg(long = "ip-version", value_enum, default_value_t = IpVersion::Both)]
+    pub ip_version: IpVersion,
+
+    /// Send the echo requests (or TCP connects) from this local address, e.g. to compare the uplinks of a
+    /// multi-homed host. Only servers of its address family are pinged
+    #[arg(long, value_name = "IP", value_parser = local_addr)]
+    pub bind: Option<IpAddr>,
+
+    /// Never ping addresses within these ranges, e.g. 10.0.0.0/8,192.168.0.0/16 - hostnames are checked once resolved
+    #[arg(long = "exclude-cidr", value_name = "CIDRS", value_delimiter = ',')]
+    pub exclude_cidr: Vec<IpNet>,
+
+    /// Time TCP connects to port 443 instead if no ICMP socket can be opened, e.g. without root or CAP_NET_RAW.
+    /// Handshakes take longer than echo replies, the Probe column tells which was measured
+    #[arg(long, value_enum, value_name = "KIND")]
+    pub fallback: Option<Fallback>,
+
+    /// Time TCP connects to this port instead of pinging, for hosts which filter or deprioritize ICMP.
+    /// Needs no raw socket, the Probe column says tcp/PORT
+    #[arg(long = "tcp-port", value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..), conflicts_with = "fallback")]
+    pub tcp_port: Option<u16>,
+
+    /// Number of ICMP echo requests sent to each server
+    #[arg(long, default_value_t = DEFAULT_PING_COUNT, value_parser = clap::value_parser!(u16).range(1..))]
+    pub count: u16,
+
+    /// Echo requests sent to each server before the --count ones, their replies are left out of the statistics
+    /// as the first ones to a cold destination also wait for ARP / neighbor discovery
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub warmup: u16,
+
+    /// Pause between two echo requests to the same server in milliseconds, bursts of them look like
+    /// a flood to some firewalls
+    #[arg(long = "probe-interval-ms", value_name = "N", default_value_t = 0)]
+    pub probe_interval_ms: u64,
+
+    /// Send at most this many echo requests per second over all servers in flight, whatever the concurrency
+    #[arg(long, value_name = "N")]
+    pub pps: Option<NonZeroU32>,
+
+    /// Size of the echo request payload, bigger packets approximate real traffic and take longer [max: 65500]
+    #[arg(long = "payload-bytes", value_name = "N", default_value_t = 0, value_parser = clap::value_parser!(u16).range(..=MAX_PAYLOAD_BYTES as i64))]
+    pub payload_bytes: u16,
+
+    /// Time to wait for a single reply in milliseconds, later replies count as lost
+    #[arg(long = "timeout-ms", default_value_t = DEFAULT_TIMEOUT_MS, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout_ms: u64,
+
+    /// Time a single server may take over all of its echo requests in milliseconds, those it leaves
+    /// no time for count as lost [default: no limit besides --timeout-ms for every reply]
+    #[arg(long = "server-budget-ms", value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub server_budget_ms: Option<u64>,
+
+    /// Leave servers whose fastest reply took longer than this many milliseconds out of the statistics,
+    /// they're listed as slow in --unreachable-out [default: anything which replies within --timeout-ms]
+    #[arg(long = "max-acceptable-rtt-ms", value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_acceptable_rtt_ms: Option<u64>,
+
+    /// Keep the servers slower than --max-acceptable-rtt-ms in the statistics, flagged in the Above Threshold
+    /// column of --detailed, to tell slow countries from unreachable ones
+    #[arg(long = "keep-slow", requires = "max_acceptable_rtt_ms")]
+    pub keep_slow: bool,
+
+    /// Number of servers of a country pinged at the same time
+    #[arg(long, default_value_t = DEFAULT_CONCURRENCY, value_parser = clap::value_parser!(u16).range(1..))]
+    pub concurrency: u16,
+
+    /// Number of countries pinged at the same time, each with up to --concurrency servers in flight
+    #[arg(long = "country-concurrency", default_value_t = DEFAULT_COUNTRY_CONCURRENCY, value_parser = clap::value_parser!(u16).range(1..))]
+    pub country_concurrency: u16,
+
+    /// File the per-country statistics are written to, missing directories are created
+    /// [default: rtt_result.csv, .json, .html or .ndjson, rtt_merged.csv for merge]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub output: Option<PathBuf>,
+
+    /// Only write the N best ranked countries, --detailed keeps only their servers; --sqlite still gets every country
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub top: Option<u32>,
+
+    /// Format of the per-country statistics, ndjson also streams every country to stdout once it's pinged
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub format: OutputFormat,
+
+    /// RTT of every server the country statistics are built from, its best reply or the mean of its replies
+    #[arg(long = "server-metric", value_enum, default_value_t = ServerMetric::Min)]
+    pub server_metric: ServerMetric,
+
+    /// Weight of packet loss in the score countries are sorted by, median RTT * (1 + loss fraction * penalty)
+    #[arg(long = "loss-penalty", value_name = "WEIGHT", default_value_t = DEFAULT_LOSS_PENALTY, value_parser = loss_penalty)]
+    pub loss_penalty: f64,
+
+    /// Number formatting and field separator of the output
+    #[arg(long, value_enum, default_value_t = OutputStyle::Csv, global = true)]
+    pub locale: OutputStyle,
+
+    /// Decimals of the RTTs, percentages and scores of the CSV outputs, e.g. 1 - replies aren't timed
+    /// much finer than to the microsecond anyway
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_PRECISION, value_parser = clap::value_parser!(u8).range(0..=9))]
+    pub precision: u8,
+
+    /// Add a Country Name column after the code to the CSV output, e.g. Germany for DE
+    #[arg(long = "with-country-name")]
+    pub with_country_name: bool,
+
+    /// Add the organization / ASN hosting every server, as found while correcting locations, to --detailed
+    #[arg(long = "enrich-asn", requires = "detailed")]
+    pub enrich_asn: bool,
+
+    /// Countries with fewer servers left are warned about, their statistics are hardly more than a single RTT
+    #[arg(long = "min-samples", value_name = "N", default_value_t = DEFAULT_MIN_SAMPLES, value_parser = clap::value_parser!(u32).range(1..))]
+    pub min_samples: u32,
+
+    /// Leave countries with fewer than --min-samples servers out of the output instead of warning about them
+    #[arg(long = "drop-small")]
+    pub drop_small: bool,
+
+    /// Also write one row per server (country, city, IP, min RTT, packet loss) to this file
+    #[arg(long, value_name = "PATH")]
+    pub detailed: Option<PathBuf>,
+
+    /// Also append every server of this run to the measurements table of this SQLite database
+    #[arg(long, value_name = "PATH")]
+    pub sqlite: Option<PathBuf>,
+
+    /// Also list servers which didn't reply or aren't valid IPs or hostnames in this file
+    #[arg(long = "unreachable-out", value_name = "PATH")]
+    pub unreachable_out: Option<PathBuf>,
+
+    /// Also write the run's parameters and totals (version, host, count, timeout, servers...) next to
+    /// the output, to <output>.meta.json
+    #[arg(long)]
+    pub meta: bool,
+
+    /// Exit with 1 once the output is written if none of the servers replied, for health checks and CI
+    #[arg(long = "fail-on-empty")]
+    pub fail_on_empty: bool,
+
+    /// Compress the output files, --detailed and --unreachable-out too, appending .gz or .zst to their names
+    #[arg(long, value_enum, default_value_t = Compression::None, global = true)]
+    pub compress: Compression,
+
+    /// What to do with servers the geo provider places in another country than their file
+    #[arg(long = "country-correction", value_enum, default_value_t = CountryCorrection::Warn)]
+    pub country_correction: CountryCorrection,
+
+    /// Trust the countries of the server files and skip every location lookup, no token needed.
+    /// Same as --country-correction off
+    #[arg(long = "no-correct", conflicts_with = "country_correction")]
+    pub no_correct: bool,
+
+    /// Trust the countries of the server files and only look servers up to fill in missing cities.
+    /// Same as --country-correction fill
+    #[arg(long = "fill-only", conflicts_with_all = ["country_correction", "no_correct"])]
+    pub fill_only: bool,
+
+    /// Where server locations are looked up to correct their countries
+    #[arg(long = "geo-provider", value_enum, default_value_t = GeoProviderKind::Ipinfo)]
+    pub geo_provider: GeoProviderKind,
+
+    /// MaxMind City database (.mmdb) used with --geo-provider maxmind
+    #[arg(long, value_name = "PATH", required_if_eq("geo_provider", "maxmind"))]
+    pub mmdb: Option<PathBuf>,
+
+    /// Also look servers up with a free service (ip-api.com) where --geo-provider can't place them,
+    /// or instead of it without a token. Opt-in since it sends the IPs to a third party
+    #[arg(long = "geo-fallback", value_enum, value_name = "KIND")]
+    pub geo_fallback: Option<GeoFallback>,
+
+    /// File keeping IpInfo lookups between runs
+    #[arg(long = "ipinfo-cache", value_name = "PATH", default_value = DEFAULT_IPINFO_CACHE)]
+    pub ipinfo_cache: PathBuf,
+
+    /// Number of IpInfo lookups in flight at the same time
+    #[arg(long = "ipinfo-concurrency", default_value_t = DEFAULT_IPINFO_CONCURRENCY, value_parser = clap::value_parser!(u16).range(1..))]
+    pub ipinfo_concurrency: u16,
+
+    /// How many times a failed IpInfo lookup is retried on timeouts or server errors
+    #[arg(long = "ipinfo-retries", default_value_t = DEFAULT_IPINFO_RETRIES)]
+    pub ipinfo_retries: u32,
+
+    /// Delay before the first retry in milliseconds, doubled on every next one
+    #[arg(long = "ipinfo-retry-delay-ms", default_value_t = DEFAULT_IPINFO_RETRY_DELAY_MS)]
+    pub ipinfo_retry_delay_ms: u64,
+
+    /// How long all IpInfo lookups pause when the rate limit is reached, in seconds
+    #[arg(long = "ipinfo-rate-limit-pause-secs", default_value_t = DEFAULT_IPINFO_RATE_LIMIT_PAUSE_SECS)]
+    pub ipinfo_rate_limit_pause_secs: u64,
+
+    /// Longest time all rate limit pauses may add up to in seconds, lookups fail once it's used up
+    #[arg(long = "ipinfo-max-wait-secs", default_value_t = DEFAULT_IPINFO_MAX_WAIT_SECS)]
+    pub ipinfo_max_wait_secs: u64,
+
+    /// Send at most this many IpInfo requests over the whole run, retries and batches included, to stay within
+    /// the monthly quota. Servers which would need more are left uncorrected [default: no limit]
+    #[arg(long = "max-requests", value_name = "N")]
+    pub max_requests: Option<u64>,
+
+    /// Stop pinging and correcting after this many seconds from the start and write what was collected so far
+    #[arg(long = "deadline-seconds", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub deadline_seconds: Option<u64>,
+
+    /// Scan again this long after every scan until Ctrl-C, e.g. 15m; every scan writes outputs named
+    /// after its timestamp, like rtt_result-1700000000.csv, and appends to --sqlite
+    #[arg(long, value_name = "DURATION", value_parser = interval, conflicts_with_all = ["deadline_seconds", "resume"])]
+    pub interval: Option<Duration>,
+
+    /// File every finished country is written to right away, removed once the output is written
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_PARTIAL)]
+    pub partial: PathBuf,
+
+    /// Skip countries already in the --partial file of an interrupted scan and reuse their results
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Config file whose keys are the long names of these flags, flags given here override it
+    /// [default: geoping.toml if present]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Only load the server files and list what was found, without pinging
+    #[arg(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Don't draw progress bars or print the top countries at the end, for CI logs
+    #[arg(long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print a line for every pinged server
+    #[arg(long)]
+    pub verbose: bool,
+}
+
+impl Args
+{
+    pub fn country_correction(&self) -> CountryCorrection
+    {
+        if self.no_correct { CountryCorrection::Off }
+        else if self.fill_only { CountryCorrection::Fill }
+        else { self.country_correction }
+    }
+
+    pub fn verbosity(&self) -> Verbosity
+    {
+        if self.quiet { Verbosity::Quiet }
+        else if self.verbose { Verbosity::Verbose }
+        else { Verbosity::Normal }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use clap::Parser;
+    use super::*;
+
+    #[test]
+    fn loss_penalty_must_be_finite_and_not_negative()
+    {
+        for value in ["NaN", "inf", "-1", "x"]
+        {
+            let arg = format!("--loss-penalty={}", value);
+
+            assert!(Args::try_parse_from(["geoping", arg.as_str()]).is_err(), "{} was accepted", value);
+        }
+
+        assert_eq!(Args::try_parse_from(["geoping", "--loss-penalty", "0"]).unwrap().loss_penalty, 0.0);
+        assert_eq!(Args::try_parse_from(["geoping", "--loss-penalty", "2.5"]).unwrap().loss_penalty, 2.5);
+    }
+
+    #[test]
+    fn interval_takes_a_unit_and_rejects_overflows()
+    {
+        assert_eq!(interval("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(interval("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(interval("2h"), Ok(Duration::from_secs(7200)));
+
+        assert!(interval("0").is_err());
+        assert!(interval("1d").is_err());
+        assert_eq!(interval(&format!("{}h", u64::MAX)), Err("interval too large".to_string()));
+    }
+
+    #[test]
+    fn inventory_and_output_flags_follow_the_subcommand_too()
+    {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let args = Args::try_parse_from(["geoping", "lint", "--input-dir", dir, "--ip-field", "address", "--glob", "*.json"]).unwrap();
+
+        assert_eq!(args.input_dirs, [PathBuf::from(dir)]);
+        assert_eq!(args.ip_field, "address");
+        assert!(args.glob.is_some());
+
+        let args = Args::try_parse_from(["geoping", "merge", "--output", "all.csv", "--locale", "eu", "a.csv"]).unwrap();
+
+        assert_eq!(args.output, Some(PathBuf::from("all.csv")));
+        assert_eq!(args.locale, OutputStyle::Tsv);
+    }
+}
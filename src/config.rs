@@ -0,0 +1,119 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use clap::{ArgMatches, Command, CommandFactory, FromArgMatches};
+use clap::error::ErrorKind;
+use serde::Deserialize;
+use crate::cli::{Args, GeoProviderKind};
+
+pub const DEFAULT_CONFIG: &str = "geoping.toml";
+
+/// Contents of geoping.toml, every key other than the token is the long name of a flag
+/// and becomes its default, so flags given on the command line still win
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config
+{
+    /// Used when GEOPING_IPINFO_TOKEN is unset
+    pub ipinfo_token: Option<String>,
+
+    #[serde(flatten)]
+    pub options: toml::Table,
+}
+
+fn config_error(cmd: &mut Command, message: String) -> !
+{
+    cmd.error(ErrorKind::InvalidValue, message).exit()
+}
+
+/// Value of --config, looked up before clap parses anything since the config
+/// decides the defaults clap is set up with
+fn config_path() -> Option<PathBuf>
+{
+    let mut args = env::args_os().skip(1);
+
+    while let Some(arg) = args.next()
+    {
+        let arg = arg.to_string_lossy().to_string();
+
+        if arg == "--" { break; }
+        if arg == "--config" { return args.next().map(PathBuf::from); }
+        if let Some(path) = arg.strip_prefix("--config=") { return Some(PathBuf::from(path)); }
+    }
+
+    None
+}
+
+/// Reads --config, or geoping.toml from the working directory when it's there
+fn load_config(cmd: &mut Command) -> Config
+{
+    let (path, required) = match config_path()
+    {
+        Some(path) => (path, true),
+        None => (PathBuf::from(DEFAULT_CONFIG), false),
+    };
+
+    let content = match fs::read_to_string(&path)
+    {
+        Ok(content) => content,
+        Err(_) if !required => return Config::default(),
+        Err(err) => config_error(cmd, format!("could not read config {}: {}", path.to_string_lossy(), err)),
+    };
+
+    match toml::from_str(content.as_str())
+    {
+        Ok(config) => config,
+        Err(err) => config_error(cmd, format!("invalid config {}: {}", path.to_string_lossy(), err)),
+    }
+}
+
+fn default_values(value: &toml::Value) -> Option<Vec<String>>
+{
+    match value
+    {
+        toml::Value::String(text) => Some(vec![text.clone()]),
+        toml::Value::Integer(number) => Some(vec![number.to_string()]),
+        toml::Value::Float(number) => Some(vec![number.to_string()]),
+        toml::Value::Boolean(flag) => Some(vec![flag.to_string()]),
+        toml::Value::Array(values) => values.iter()
+            .map(|value| default_values(value).and_then(|values| values.into_iter().next()))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Parses the command line on top of the config file
+pub fn parse_args() -> (Args, Config)
+{
+    let mut cmd = Args::command();
+    let config = load_config(&mut cmd);
+
+    for (key, value) in config.options.iter()
+    {
+        let Some(id) = cmd.get_arguments().find(|arg| arg.get_long() == Some(key.as_str())).map(|arg| arg.get_id().clone()) else {
+            config_error(&mut cmd, format!("unknown config key '{}'", key));
+        };
+
+        let Some(values) = default_values(value) else {
+            config_error(&mut cmd, format!("config key '{}' has to be a string, number, boolean or a list of them", key));
+        };
+
+        cmd = cmd.mut_arg(id, |arg| arg.default_values(values));
+    }
+
+    let matches: ArgMatches = cmd.get_matches_mut();
+
+    let args = match Args::from_arg_matches(&matches)
+    {
+        Ok(args) => args,
+        Err(err) => err.format(&mut cmd).exit(),
+    };
+
+    // clap only checks this for values given on the command line
+    if args.geo_provider == GeoProviderKind::Maxmind && args.mmdb.is_none()
+    {
+        cmd.error(ErrorKind::MissingRequiredArgument, "--geo-provider maxmind needs --mmdb <PATH>").exit();
+    }
+
+    (args, config)
+}
@@ -0,0 +1,58 @@
+use std::io;
+use std::path::PathBuf;
+use ipinfo::IpError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GeopingError
+{
+    #[error("could not read {}: {source}", path.to_string_lossy())]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("could not write {}: {source}", path.to_string_lossy())]
+    Write { path: PathBuf, source: io::Error },
+
+    #[error("could not parse {}: {source}", path.to_string_lossy())]
+    Json { path: PathBuf, source: serde_json::Error },
+
+    #[error("could not parse {}: expected an array of servers or an object of cities", path.to_string_lossy())]
+    UnsupportedShape { path: PathBuf },
+
+    #[error("could not parse {}: expected an object of countries", path.to_string_lossy())]
+    CombinedShape { path: PathBuf },
+
+    #[error("{} is not a geoping CSV output: {problem}", path.to_string_lossy())]
+    NotResults { path: PathBuf, problem: String },
+
+    #[error("could not parse line {line} of {}", path.to_string_lossy())]
+    ResultsRow { path: PathBuf, line: usize },
+
+    #[error("could not open an ICMP socket: {0}")]
+    PingClient(io::Error),
+
+    #[error("IpInfo: {0}")]
+    IpInfo(#[from] IpError),
+
+    #[error("could not write to SQLite database {}: {source}", path.to_string_lossy())]
+    Sqlite { path: PathBuf, source: rusqlite::Error },
+}
+
+impl GeopingError
+{
+    /// Exit code of a run failing with this error, as in sysexits.h
+    pub fn exit_code(&self) -> i32
+    {
+        match self
+        {
+            GeopingError::Read { .. } => 66,// EX_NOINPUT
+            GeopingError::Json { .. }
+            | GeopingError::UnsupportedShape { .. }
+            | GeopingError::CombinedShape { .. }
+            | GeopingError::NotResults { .. }
+            | GeopingError::ResultsRow { .. } => 65,// EX_DATAERR
+            GeopingError::Write { .. } | GeopingError::Sqlite { .. } => 73,// EX_CANTCREAT
+            GeopingError::PingClient(_) => 77,// EX_NOPERM
+            GeopingError::IpInfo(_) => 69,// EX_UNAVAILABLE
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use async_trait::async_trait;
+use ipinfo::IpDetails;
+use maxminddb::{geoip2, Reader};
+use crate::IP;
+
+pub type GeoError = Box<dyn Error + Send + Sync>;
+
+/// IPs a provider answered since it was opened, from its cache or by querying its service
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats
+{
+    pub hits: u64,// answered from the cache, including cached failures
+    pub misses: u64,// not in the cache, so looked up over the network
+    pub requests: u64,// sent to the service, retries and batches of many IPs included - what the quota counts
+}
+
+impl CacheStats
+{
+    /// What was answered after `earlier` was taken
+    pub fn since(&self, earlier: CacheStats) -> CacheStats
+    {
+        CacheStats { hits: self.hits - earlier.hits, misses: self.misses - earlier.misses, requests: self.requests - earlier.requests }
+    }
+}
+
+/// Source of server locations, used to correct countries and fill missing cities
+#[async_trait]
+pub trait GeoProvider: Send + Sync
+{
+    /// Only `country`, `city` and `loc` have to be filled, an empty country means unknown
+    async fn query(&self, ip: &str) -> Result<IpDetails, GeoError>;
+
+    /// Looks up many IPs at once where the provider can, the ones missing from the result
+    /// are queried one by one afterwards. Nothing is looked up in bulk by default.
+    async fn query_batch(&self, _ips: &[&str]) -> HashMap<IP, IpDetails>
+    {
+        HashMap::new()
+    }
+
+    /// Cache hits and misses so far, None for providers without a cache
+    fn cache_stats(&self) -> Option<CacheStats>
+    {
+        None
+    }
+
+    /// Persists whatever the provider cached, nothing by default
+    fn flush(&self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+/// Asks `fallback` about whatever `primary` can't place, e.g. a free service behind a local database
+pub struct FallbackProvider
+{
+    primary: Box<dyn GeoProvider>,
+    fallback: Box<dyn GeoProvider>,
+}
+
+impl FallbackProvider
+{
+    pub fn new(primary: Box<dyn GeoProvider>, fallback: Box<dyn GeoProvider>) -> FallbackProvider
+    {
+        FallbackProvider { primary, fallback }
+    }
+}
+
+#[async_trait]
+impl GeoProvider for FallbackProvider
+{
+    async fn query(&self, ip: &str) -> Result<IpDetails, GeoError>
+    {
+        match self.primary.query(ip).await
+        {
+            Ok(details) if !details.country.is_empty() => Ok(details),
+            _ => self.fallback.query(ip).await,
+        }
+    }
+
+    async fn query_batch(&self, ips: &[&str]) -> HashMap<IP, IpDetails>
+    {
+        let mut found = self.primary.query_batch(ips).await;
+        found.retain(|_, details| !details.country.is_empty());
+
+        let missing: Vec<&str> = ips.iter().filter(|ip| !found.contains_key(**ip)).cloned().collect();
+        found.extend(self.fallback.query_batch(&missing).await);
+
+        found
+    }
+
+    /// Of the primary provider, the fallback is only asked about what it misses
+    fn cache_stats(&self) -> Option<CacheStats>
+    {
+        self.primary.cache_stats()
+    }
+
+    fn flush(&self) -> io::Result<()>
+    {
+        self.fallback.flush()?;
+        self.primary.flush()
+    }
+}
+
+/// Offline lookups in a local GeoLite2 / GeoIP2 City database
+pub struct MaxMindProvider
+{
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMindProvider
+{
+    pub fn open(path: &Path) -> Result<MaxMindProvider, GeoError>
+    {
+        Ok(MaxMindProvider {
+            reader: Reader::open_readfile(path)?
+        })
+    }
+}
+
+#[async_trait]
+impl GeoProvider for MaxMindProvider
+{
+    async fn query(&self, ip: &str) -> Result<IpDetails, GeoError>
+    {
+        let addr: IpAddr = ip.parse()?;
+
+        let record = self.reader
+            .lookup(addr)?
+            .decode::<geoip2::City>()?
+            .ok_or_else(|| format!("{} is not in the database", ip))?;
+
+        let loc = match (record.location.latitude, record.location.longitude)
+        {
+            (Some(latitude), Some(longitude)) => format!("{},{}", latitude, longitude),
+            _ => String::new(),
+        };
+
+        Ok(IpDetails {
+            ip: ip.to_string(),
+            city: record.city.names.english.unwrap_or_default().to_string(),
+            country: record.country.iso_code.unwrap_or_default().to_string(),
+            loc,
+            ..Default::default()
+        })
+    }
+}
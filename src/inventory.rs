@@ -0,0 +1,780 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use log::{error, info, warn};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use flate2::read::GzDecoder;
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+use serde_json::Value;
+use crate::{City, CountryCode, IP};
+use crate::error::GeopingError;
+
+/// File name without its extension, uppercased - `de.json` and `de.json.gz` are both DE.
+/// Anything else in the name is kept as is, so `us-east.json` is US-EAST and `us.east.json` is US.EAST,
+/// regions are listed on their own rather than merged into their country.
+fn obtain_country_code_from_filepath(path: &Path) -> CountryCode
+{
+    let path = match path.extension()
+    {
+        Some(ext) if ext == "gz" => Path::new(path.file_stem().unwrap()),
+        _ => path,
+    };
+
+    path.file_stem().unwrap().to_string_lossy().to_uppercase()
+}
+
+/// Country a key of [`obtain_country_code_from_filepath`] stands for, the part before the first `-` or `.`,
+/// so servers of the US-EAST and US.EAST region files are in US
+pub fn iso_country_code(cc: &str) -> &str
+{
+    cc.split(['-', '.']).next().unwrap_or(cc)
+}
+
+/// Extension telling how the content is read, the one before `.gz` for compressed files
+fn content_extension(path: &Path) -> Option<String>
+{
+    match path.extension()
+    {
+        Some(ext) if ext == "gz" => Path::new(path.file_stem()?).extension().map(|ext| ext.to_string_lossy().to_string()),
+        ext => ext.map(|ext| ext.to_string_lossy().to_string()),
+    }
+}
+
+/// Walks `dir` and its subdirectories, symlinked directories are not followed
+fn gather_files_with_ext(dir: &Path, extensions: &[&str]) -> Vec<PathBuf>
+{
+    let mut paths = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop()
+    {
+        if let Ok(dir) = fs::read_dir(&dir)
+        {
+            for file in dir.flatten()
+            {
+                if let Ok(kind) = file.file_type()
+                {
+                    let path = file.path();
+
+                    if kind.is_dir()
+                    {
+                        dirs.push(path);
+                    }
+                    // regular file
+                    else if kind.is_file()
+                    {
+                        if let Some(ext) = path.extension()
+                        {
+                            if extensions.iter().any(|extension| ext == *extension)
+                            {
+                                paths.push(path);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    paths
+}
+
+/// Servers of every country, as listed in its file
+pub type Servers = HashMap<CountryCode, Vec<(City, IP)>>;
+
+/// Servers pinged at once within the countries whose file asks for it with `_concurrency`
+pub type Concurrency = HashMap<CountryCode, usize>;
+
+/// Names of the fields servers are read from in arrays of objects, `ip` and `city` by default
+#[derive(Debug, Clone)]
+pub struct EntryFields
+{
+    pub ip: String,
+    pub city: String,
+}
+
+impl Default for EntryFields
+{
+    fn default() -> EntryFields
+    {
+        EntryFields { ip: "ip".to_string(), city: "city".to_string() }
+    }
+}
+
+/// Problem with a single entry of a server file, the entry is skipped
+#[derive(Debug, Clone)]
+pub struct Diagnostic
+{
+    pub path: PathBuf,
+    pub entry: String,// "#3" in arrays, "city Berlin" in objects, "line 4" in CSV files, empty for the whole file
+    pub problem: String,
+}
+
+/// Why a required string field can't be used, with a hint if only its case is off
+fn field_problem(entry: &Value, field: &str) -> Option<String>
+{
+    match entry.get(field)
+    {
+        Some(Value::String(_)) => None,
+        Some(value) => Some(format!("\"{}\" is {}, expected a string", field, json_type(value))),
+        None => {
+            let similar = entry.as_object()
+                .and_then(|object| object.keys().find(|key| key.eq_ignore_ascii_case(field)));
+
+            match similar
+            {
+                Some(key) => Some(format!("missing \"{}\", found \"{}\" - field names are case-sensitive", field, key)),
+                None => Some(format!("missing \"{}\"", field)),
+            }
+        }
+    }
+}
+
+fn json_type(value: &Value) -> &'static str
+{
+    match value
+    {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Fields of a CSV line, separated by commas outside of double quotes, `""` within them is a quote.
+/// None if a quote is left open.
+fn split_csv_line(line: &str) -> Option<Vec<String>>
+{
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next()
+    {
+        match c
+        {
+            '"' if quoted && chars.peek() == Some(&'"') => { field.push('"'); chars.next(); }
+            '"' => { quoted = !quoted; }
+            ',' if !quoted => { fields.push(field.trim().to_string()); field.clear(); }
+            _ => { field.push(c); }
+        }
+    }
+
+    if quoted { return None; }
+
+    fields.push(field.trim().to_string());
+
+    Some(fields)
+}
+
+/// `city,ip` rows, a first row like `city,ip` is taken as a header and skipped. Lines starting with `#`
+/// are comments, fields can be quoted like `"Washington, D.C.",1.2.3.4`
+fn parse_csv(content: &str, problems: &mut Vec<(String, String)>) -> Vec<(City, IP)>
+{
+    let mut cities = vec![];
+    let mut any_row = false;
+
+    // left at the start by some spreadsheet exports
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+
+    for (i, line) in content.lines().enumerate()
+    {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') { continue; }
+
+        // the header, if there's one, is the first row which isn't a comment
+        let first_row = !std::mem::replace(&mut any_row, true);
+
+        let Some(fields) = split_csv_line(line) else {
+            problems.push((format!("line {}", i + 1), "a quote is never closed".to_string()));
+            continue;
+        };
+
+        match fields.as_slice()
+        {
+            [city, ip, ..] if !city.is_empty() && !ip.is_empty() => {
+                if first_row && (city.eq_ignore_ascii_case("city") || ip.eq_ignore_ascii_case("ip")) { continue; }
+
+                cities.push((city.clone(), ip.clone()));
+            }
+            _ => { problems.push((format!("line {}", i + 1), "expected city,ip".to_string())); }
+        }
+    }
+
+    cities
+}
+
+/// CSVs written by geoping start with a `Country` column, the --output or --detailed one
+/// of an earlier run for example, in either of the --locale styles
+fn is_geoping_output(content: &str) -> bool
+{
+    let header = content.lines().find(|line| !line.trim().is_empty()).unwrap_or_default();
+    let first = header.split([',', '\t']).next().unwrap_or_default();
+
+    first.trim().trim_start_matches('\u{feff}').trim_matches('"') == "Country"
+}
+
+/// Content of a file, decompressed if it's gzipped
+fn read_content(path: &Path) -> Result<String, GeopingError>
+{
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map_err(|source| GeopingError::Read { path: path.to_path_buf(), source })?;
+
+    let mut reader: Box<dyn Read> = match path.extension()
+    {
+        Some(ext) if ext == "gz" => Box::new(GzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+
+    let mut content = String::new();
+    reader.read_to_string(&mut content)
+        .map_err(|source| GeopingError::Read { path: path.to_path_buf(), source })?;
+
+    Ok(content)
+}
+
+/// Servers listed for a single country
+struct Listing
+{
+    cities: Vec<(City, IP)>,
+    concurrency: Option<usize>,// asked for with `_concurrency`
+}
+
+/// Servers of a single country, None if `json` is neither of the supported shapes.
+/// Entries which had to be skipped are labelled starting with `prefix`.
+fn parse_json_servers(json: &Value, prefix: &str, fields: &EntryFields, problems: &mut Vec<(String, String)>) -> Option<Listing>
+{
+    let mut cities = vec![];
+
+    match json
+    {
+        // { "_concurrency": 4, "servers": <either of the shapes below> }
+        Value::Object(entries) if entries.get("servers").is_some_and(|servers| servers.is_array() || servers.is_object()) => {
+            let cities = parse_json_servers(&entries["servers"], prefix, fields, problems)?.cities;
+
+            let concurrency = match entries.get("_concurrency")
+            {
+                Some(value) => match value.as_u64().filter(|concurrency| *concurrency > 0)
+                {
+                    Some(concurrency) => Some(concurrency as usize),
+                    None => {
+                        problems.push((format!("{}_concurrency", prefix), format!("is {}, expected a positive integer - using --concurrency", value)));
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            return Some(Listing { cities, concurrency });
+        }
+        // [{ "ip": "1.2.3.4", "city": "Berlin", ... }, ...] as exported by public-dns.info
+        Value::Array(entries) => {
+            for (i, entry) in entries.iter().enumerate()
+            {
+                if !entry.is_object()
+                {
+                    problems.push((format!("{}#{}", prefix, i), format!("is {}, expected an object", json_type(entry))));
+                    continue;
+                }
+
+                // anything besides the IP and the city is left alone, public-dns.info exports have plenty
+                match (entry[fields.ip.as_str()].as_str(), entry[fields.city.as_str()].as_str())
+                {
+                    (Some(ip), Some(city)) => { cities.push((city.to_string(), ip.to_string())); }
+                    _ => {
+                        let found = [&fields.ip, &fields.city].into_iter().filter_map(|field| field_problem(entry, field));
+                        problems.extend(found.map(|problem| (format!("{}#{}", prefix, i), problem)));
+                    }
+                }
+            }
+        }
+        // { "Berlin": "1.2.3.4", ... }
+        Value::Object(entries) => {
+            for (city, ip) in entries.iter()
+            {
+                match ip.as_str()
+                {
+                    Some(ip) => { cities.push((city.clone(), ip.to_string())); }
+                    None => { problems.push((format!("{}city {}", prefix, city), format!("IP is {}, expected a string", json_type(ip)))); }
+                }
+            }
+        }
+        _ => { return None; }
+    }
+
+    Some(Listing { cities, concurrency: None })
+}
+
+/// Servers of a file along with the entries which had to be skipped, None for a CSV geoping wrote itself
+fn load_servers_file(path: &Path, fields: &EntryFields, problems: &mut Vec<(String, String)>) -> Result<Option<Listing>, GeopingError>
+{
+    let content = read_content(path)?;
+
+    if content_extension(path).is_some_and(|ext| ext == "csv")
+    {
+        if is_geoping_output(content.as_str()) { return Ok(None); }
+
+        return Ok(Some(Listing { cities: parse_csv(content.as_str(), problems), concurrency: None }));
+    }
+
+    let json: Value = serde_json::from_str(content.as_str())
+        .map_err(|source| GeopingError::Json { path: path.to_path_buf(), source })?;
+
+    parse_json_servers(&json, "", fields, problems).map(Some).ok_or_else(|| GeopingError::UnsupportedShape { path: path.to_path_buf() })
+}
+
+/// Logs the skipped entries of a file together, problems of the whole file are logged where they happen
+fn report_problems(path: &Path, problems: &[(String, String)])
+{
+    if problems.iter().any(|(entry, _)| !entry.is_empty())
+    {
+        let mut entries: Vec<&str> = problems.iter().map(|(entry, _)| entry.as_str()).collect();
+        entries.dedup();
+
+        warn!("Skipping {} entries of {}:", entries.len(), path.to_string_lossy());
+
+        for (entry, problem) in problems.iter()
+        {
+            warn!("  {}: {}", entry, problem);
+        }
+    }
+}
+
+/// Loads servers of every country from a single file shaped `{ "DE": [...], "PL": [...] }`, where every
+/// country holds what a server file would - an array of servers, an object of cities or either of them
+/// wrapped with a `_concurrency`, see [`collect_servers`]. Countries of another shape are skipped like
+/// bad entries, a file which can't be read or parsed is an error.
+pub fn load_combined(path: &Path, fields: &EntryFields) -> Result<(Servers, Vec<Diagnostic>, Concurrency), GeopingError>
+{
+    let json: Value = serde_json::from_str(read_content(path)?.as_str())
+        .map_err(|source| GeopingError::Json { path: path.to_path_buf(), source })?;
+
+    let Value::Object(countries) = json else {
+        return Err(GeopingError::CombinedShape { path: path.to_path_buf() });
+    };
+
+    let mut servers: Servers = HashMap::new();
+    let mut concurrency = HashMap::new();
+    let mut problems = Vec::new();
+
+    for (cc, json) in countries.iter()
+    {
+        let cc = cc.trim().to_uppercase();
+
+        match parse_json_servers(json, format!("{} ", cc).as_str(), fields, &mut problems)
+        {
+            Some(Listing { cities, concurrency: asked }) => {
+                if let Some(asked) = asked { set_concurrency(&mut concurrency, &cc, asked); }
+
+                servers.entry(cc).or_default().extend(cities);
+            }
+            None => { problems.push((cc, "expected an array of servers or an object of cities".to_string())); }
+        }
+    }
+
+    report_problems(path, &problems);
+
+    info!("Loaded {} countries", servers.len());
+
+    let diagnostics = problems.into_iter().map(|(entry, problem)| Diagnostic { path: path.to_path_buf(), entry, problem }).collect();
+
+    Ok((servers, diagnostics, concurrency))
+}
+
+/// A country listed in several files asking for different concurrencies gets the lowest,
+/// as it likely protects a gateway which drops pings otherwise
+fn set_concurrency(concurrency: &mut Concurrency, cc: &CountryCode, asked: usize)
+{
+    match concurrency.get(cc)
+    {
+        Some(set) if *set != asked => {
+            warn!("{} asks for a concurrency of {} and {} in different files, using the lower one", cc, set, asked);
+            concurrency.insert(cc.clone(), asked.min(*set));
+        }
+        Some(_) => {}
+        None => { concurrency.insert(cc.clone(), asked); }
+    }
+}
+
+/// Server files of a single directory of [`collect_servers`]
+fn collect_dir(dir: &Path, exclude: &[PathBuf], fields: &EntryFields, pattern: Option<&Pattern>, concurrency: &mut Concurrency, diagnostics: &mut Vec<Diagnostic>) -> Servers
+{
+    let mut countries: Servers = HashMap::new();
+
+    // `*` stays within a directory, `**/` goes into subdirectories
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+
+    let paths: Vec<PathBuf> = gather_files_with_ext(dir, &["json", "csv", "gz"])
+        .into_iter()
+        .filter(|path| content_extension(path).is_some_and(|ext| ext == "json" || ext == "csv"))
+        .filter(|path| pattern.is_none_or(|pattern| path.strip_prefix(dir).is_ok_and(|path| pattern.matches_path_with(path, options))))
+        .filter(|path| fs::canonicalize(path).is_ok_and(|path| !exclude.contains(&path)))
+        .collect();
+
+    info!("Server files should be placed at {}", dir.to_string_lossy());
+
+    for path in paths
+    {
+        let cc = obtain_country_code_from_filepath(&path);
+
+        let mut problems = Vec::new();
+
+        match load_servers_file(&path, fields, &mut problems)
+        {
+            Ok(None) => { info!("Skipping {}, it's a geoping output rather than a server file", path.to_string_lossy()); }
+            Ok(Some(Listing { cities, concurrency: asked })) => {
+                if let Some(asked) = asked { set_concurrency(concurrency, &cc, asked); }
+
+                // de.json next to DE.csv, or europe/de.json next to backup/de.json
+                if let Some(listed) = countries.get(&cc)
+                {
+                    info!("{} is listed in another file too, adding {} servers of {} to its {}", cc, cities.len(), path.to_string_lossy(), listed.len());
+                }
+
+                countries.entry(cc).or_default().extend(cities);
+            }
+            Err(err) => {
+                error!("Skipping country {}, {}", cc, err);
+                problems.push((String::new(), err.to_string()));
+            }
+        }
+
+        report_problems(&path, &problems);
+
+        diagnostics.extend(problems.into_iter().map(|(entry, problem)| Diagnostic { path: path.clone(), entry, problem }));
+    }
+
+    countries
+}
+
+/// Loads every `.json` and `.csv` server file under each of `dirs`, gzipped ones too, keyed by the country code from its name.
+/// A country found in more than one file or directory gets the servers of all of them. Files which can't be read
+/// or parsed and entries without the string fields named by `fields` are skipped, so are the `exclude`d
+/// files - outputs of a previous run written next to the server files. Given a `pattern`, only the files
+/// whose path within their directory matches it are loaded, e.g. `??.json` or `servers/*.json`.
+/// What was skipped is logged grouped by file and returned, for callers which would rather abort.
+///
+/// JSON files can also wrap their servers as `{ "_concurrency": 4, "servers": [...] }` to ping that many
+/// of them at once instead of the usual concurrency, e.g. behind a rate-limited gateway. Those asked for
+/// are returned by country.
+pub fn collect_servers(dirs: &[PathBuf], exclude: &[PathBuf], fields: &EntryFields, pattern: Option<&Pattern>) -> (Servers, Vec<Diagnostic>, Concurrency)
+{
+    let mut countries: Servers = HashMap::new();
+    let mut concurrency = HashMap::new();
+    let mut diagnostics = Vec::new();
+
+    let exclude: Vec<PathBuf> = exclude.iter().filter_map(|path| fs::canonicalize(path).ok()).collect();
+
+    for dir in dirs
+    {
+        for (cc, cities) in collect_dir(dir, &exclude, fields, pattern, &mut concurrency, &mut diagnostics)
+        {
+            match countries.get_mut(&cc)
+            {
+                Some(listed) => {
+                    info!("{} is listed in another directory too, adding {} servers of {} to its {}", cc, cities.len(), dir.to_string_lossy(), listed.len());
+                    listed.extend(cities);
+                }
+                None => { countries.insert(cc, cities); }
+            }
+        }
+    }
+
+    info!("Loaded {} countries", countries.len());
+
+    (countries, diagnostics, concurrency)
+}
+
+/// Keeps only the given countries, warning about codes without a server file
+pub fn filter_countries(servers: &mut HashMap<CountryCode, Vec<(City, IP)>>, codes: &[CountryCode])
+{
+    let codes: Vec<CountryCode> = codes.iter().map(|cc| cc.trim().to_uppercase()).collect();
+
+    let unmatched: Vec<&str> = codes.iter()
+        .filter(|cc| !servers.contains_key(*cc))
+        .map(|cc| cc.as_str())
+        .collect();
+
+    if !unmatched.is_empty()
+    {
+        warn!("No servers for countries: {}", unmatched.join(", "));
+    }
+
+    servers.retain(|cc, _| codes.contains(cc));
+}
+
+/// Keeps only servers whose city matches `pattern`, servers without a city only if it matches an empty one.
+/// Countries left without servers are dropped.
+pub fn filter_cities(servers: &mut HashMap<CountryCode, Vec<(City, IP)>>, pattern: &Regex)
+{
+    let mut countries: Vec<_> = servers.iter_mut().collect();
+    countries.sort_by_key(|(cc, _)| *cc);
+
+    for (cc, cities) in countries
+    {
+        let total = cities.len();
+
+        cities.retain(|(city, _)| pattern.is_match(city.trim()));
+
+        info!("{}: {} of {} servers in matching cities", cc, cities.len(), total);
+    }
+
+    servers.retain(|_, cities| !cities.is_empty());
+}
+
+/// Caps every country at `max` servers, the first ones listed unless a `seed` is given,
+/// then a random pick which is the same for the same seed and server files
+pub fn sample_servers(servers: &mut HashMap<CountryCode, Vec<(City, IP)>>, max: usize, seed: Option<u64>)
+{
+    let mut countries: Vec<_> = servers.iter_mut().collect();
+    countries.sort_by_key(|(cc, _)| *cc);
+
+    for (cc, cities) in countries
+    {
+        if cities.len() <= max { continue; }
+
+        let total = cities.len();
+
+        if let Some(seed) = seed
+        {
+            cities.shuffle(&mut StdRng::seed_from_u64(seed));
+        }
+
+        cities.truncate(max);
+
+        info!("{}: sampled {} of {} servers", cc, max, total);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf
+    {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    #[test]
+    fn broken_file_does_not_stop_the_others()
+    {
+        let (servers, diagnostics, _) = collect_servers(&[fixture("broken")], &[], &EntryFields::default(), None);
+
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers["DE"], vec![("Berlin".to_string(), "192.0.2.1".to_string())]);
+        assert_eq!(servers["PL"], vec![("Warsaw".to_string(), "198.51.100.1".to_string())]);
+        assert!(!servers.contains_key("FR"));
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].path.ends_with("fr.json") && diagnostics[0].entry.is_empty());
+    }
+
+    #[test]
+    fn both_json_shapes_give_the_same_servers()
+    {
+        let array = load_servers_file(&fixture("shapes/array.json"), &EntryFields::default(), &mut vec![]).unwrap().unwrap().cities;
+
+        assert_eq!(array, load_servers_file(&fixture("shapes/cities.json"), &EntryFields::default(), &mut vec![]).unwrap().unwrap().cities);
+        assert_eq!(array, [
+            ("Berlin".to_string(), "192.0.2.1".to_string()),
+            ("Munich".to_string(), "192.0.2.2".to_string()),
+            ("Hamburg".to_string(), "2001:db8::1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn excluded_files_are_not_loaded()
+    {
+        let (servers, _, _) = collect_servers(&[fixture("broken")], &[fixture("broken/pl.json")], &EntryFields::default(), None);
+
+        assert_eq!(servers.keys().collect::<Vec<_>>(), ["DE"]);
+    }
+
+    #[test]
+    fn gathers_files_of_every_subdirectory()
+    {
+        let dir = fixture("tree");
+
+        let relative = |extensions: &[&str]| {
+            let mut paths: Vec<PathBuf> = gather_files_with_ext(&dir, extensions).iter()
+                .map(|path| path.strip_prefix(&dir).unwrap().to_path_buf())
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(relative(&["json", "csv"]), [Path::new("de.json"), Path::new("europe/pl.csv"), Path::new("europe/west/fr.json")]);
+        assert_eq!(relative(&["json"]), [Path::new("de.json"), Path::new("europe/west/fr.json")]);
+        assert_eq!(relative(&["txt"]), [Path::new("notes/todo.txt")]);
+        assert!(relative(&["toml"]).is_empty());
+    }
+
+    #[test]
+    fn skips_csv_outputs_of_earlier_runs()
+    {
+        let (servers, diagnostics, _) = collect_servers(&[fixture("outputs")], &[], &EntryFields::default(), None);
+
+        let mut countries: Vec<&CountryCode> = servers.keys().collect();
+        countries.sort();
+
+        assert_eq!(countries, ["DE", "PL"]);
+        assert_eq!(servers["DE"].len(), 2);
+        assert_eq!(servers["PL"], [("Warsaw".to_string(), "198.51.100.1".to_string())]);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn csv_rows_may_be_quoted_and_commented()
+    {
+        let csv = "\u{feff}# exported 2024-01-01\n\ncity,ip\n\"Washington, D.C.\",192.0.2.1\n\"The \"\"Big\"\" Apple\", 192.0.2.2 ,extra\n# Berlin,192.0.2.3\n\"Boston,192.0.2.4\nBoston\n";
+
+        let mut problems = vec![];
+
+        assert_eq!(parse_csv(csv, &mut problems), [
+            ("Washington, D.C.".to_string(), "192.0.2.1".to_string()),
+            ("The \"Big\" Apple".to_string(), "192.0.2.2".to_string()),
+        ]);
+        assert_eq!(problems, [
+            ("line 7".to_string(), "a quote is never closed".to_string()),
+            ("line 8".to_string(), "expected city,ip".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn only_the_first_row_can_be_a_header()
+    {
+        assert_eq!(parse_csv("# servers\nBerlin,192.0.2.1\ncity,ip\n", &mut vec![]), [
+            ("Berlin".to_string(), "192.0.2.1".to_string()),
+            ("city".to_string(), "ip".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn field_problem_explains_missing_and_mistyped_fields()
+    {
+        let entry = serde_json::json!({ "IP": "192.0.2.1", "city": 7, "name": "ns1.example.", "port": 53 });
+
+        assert_eq!(field_problem(&entry, "ip").unwrap(), "missing \"ip\", found \"IP\" - field names are case-sensitive");
+        assert_eq!(field_problem(&entry, "address").unwrap(), "missing \"address\"");
+        assert_eq!(field_problem(&entry, "city").unwrap(), "\"city\" is a number, expected a string");
+        assert_eq!(field_problem(&serde_json::json!({ "ip": null }), "ip").unwrap(), "\"ip\" is null, expected a string");
+        assert_eq!(field_problem(&serde_json::json!({ "ip": ["192.0.2.1"] }), "ip").unwrap(), "\"ip\" is an array, expected a string");
+
+        // fields besides the ones asked for don't matter
+        assert_eq!(field_problem(&entry, "name"), None);
+    }
+
+    #[test]
+    fn entries_with_field_problems_are_skipped_alone()
+    {
+        let mut problems = vec![];
+
+        let cities = load_servers_file(&fixture("entries/de.json"), &EntryFields::default(), &mut problems).unwrap().unwrap().cities;
+
+        assert_eq!(cities, [("Berlin".to_string(), "192.0.2.1".to_string())]);
+        assert_eq!(problems, [
+            ("#1".to_string(), "missing \"city\"".to_string()),
+            ("#2".to_string(), "\"ip\" is a number, expected a string".to_string()),
+            ("#3".to_string(), "is a string, expected an object".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn reads_gzipped_files_decompressed()
+    {
+        let cities = load_servers_file(&fixture("gzip/pl.csv.gz"), &EntryFields::default(), &mut vec![]).unwrap().unwrap().cities;
+
+        assert_eq!(cities, [
+            ("Warsaw".to_string(), "198.51.100.1".to_string()),
+            ("Krakow".to_string(), "198.51.100.2".to_string()),
+        ]);
+
+        // only the extension tells whether it's gzipped
+        assert!(matches!(load_servers_file(&fixture("gzip/not-gzipped.csv.gz"), &EntryFields::default(), &mut vec![]), Err(GeopingError::Read { .. })));
+        assert_eq!(obtain_country_code_from_filepath(&fixture("gzip/pl.csv.gz")), "PL");
+    }
+
+    #[test]
+    fn country_code_is_the_name_without_its_extension()
+    {
+        let cc = |path: &str| obtain_country_code_from_filepath(Path::new(path));
+
+        assert_eq!(cc("de.json"), "DE");
+        assert_eq!(cc("servers/europe/pl.csv"), "PL");
+        assert_eq!(cc("us-east.json"), "US-EAST");
+
+        // only the last extension is cut off, or the last two for gzipped files
+        assert_eq!(cc("us.east.json"), "US.EAST");
+        assert_eq!(cc("de.json.gz"), "DE");
+        assert_eq!(cc("de.backup.json.gz"), "DE.BACKUP");
+
+        assert_eq!(cc("de"), "DE");
+        assert_eq!(cc("servers/fr"), "FR");
+    }
+
+    #[test]
+    fn regions_are_in_the_country_before_the_dash_or_dot()
+    {
+        assert_eq!(iso_country_code("US-EAST"), "US");
+        assert_eq!(iso_country_code("US.EAST"), "US");
+        assert_eq!(iso_country_code("DE.BACKUP-2"), "DE");
+        assert_eq!(iso_country_code("PL"), "PL");
+    }
+
+    #[test]
+    fn loads_every_country_of_a_combined_file()
+    {
+        let (servers, diagnostics, concurrency) = load_combined(&fixture("combined/servers.json"), &EntryFields::default()).unwrap();
+
+        let mut countries: Vec<&CountryCode> = servers.keys().collect();
+        countries.sort();
+
+        assert_eq!(countries, ["DE", "FR", "PL"]);
+        assert_eq!(servers["DE"], [("Berlin".to_string(), "192.0.2.1".to_string())]);
+        assert_eq!(servers["PL"].len(), 2);
+        assert_eq!(servers["FR"], [("Paris".to_string(), "203.0.113.1".to_string())]);
+
+        let entries: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.entry.as_str()).collect();
+        assert_eq!(entries, ["DE #1", "XX"]);
+        assert_eq!(concurrency, HashMap::from([("FR".to_string(), 2)]));
+    }
+
+    #[test]
+    fn combined_file_must_be_an_object_of_countries()
+    {
+        assert!(matches!(load_combined(&fixture("combined/not-combined.json"), &EntryFields::default()), Err(GeopingError::CombinedShape { .. })));
+        assert!(matches!(load_combined(&fixture("broken/fr.json"), &EntryFields::default()), Err(GeopingError::Json { .. })));
+    }
+
+    #[test]
+    fn files_naming_the_same_country_are_merged()
+    {
+        let (servers, diagnostics, _) = collect_servers(&[fixture("same-code")], &[], &EntryFields::default(), None);
+
+        let mut cities: Vec<&str> = servers["DE"].iter().map(|(city, _)| city.as_str()).collect();
+        cities.sort();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(cities, ["Berlin", "Hamburg", "Munich"]);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn countries_of_several_dirs_are_merged()
+    {
+        let (servers, _, _) = collect_servers(&[fixture("broken"), fixture("same-code")], &[], &EntryFields::default(), None);
+
+        assert_eq!(servers["DE"].len(), 4);
+        assert_eq!(servers["PL"].len(), 1);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+use async_trait::async_trait;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use ipinfo::IpDetails;
+use log::warn;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use tokio::time::Instant;
+use crate::IP;
+use crate::geo::{GeoError, GeoProvider};
+
+// the free endpoints are plain HTTP only
+const API_URL: &str = "http://ip-api.com";
+const FIELDS: &str = "status,message,countryCode,city,lat,lon,as,query";
+const BATCH_SIZE: usize = 100;// IPs per batch request, the most the API takes
+const REQUESTS_PER_MINUTE: u32 = 45;// of /json, an IP going over it gets banned for a while
+const BATCHES_PER_MINUTE: u32 = 15;// of /batch
+const RATE_LIMITED_RETRIES: u32 = 2;// of a request refused with a 429, each after the pause it asks for
+const RATE_LIMITED_PAUSE_SECS: u64 = 60;// when a 429 doesn't say how long, the length of the window
+
+/// Answer of ip-api.com for a single IP
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Location
+{
+    status: String,// "success" or "fail"
+    #[serde(default)]
+    message: String,// why it failed, e.g. "reserved range"
+    #[serde(default)]
+    country_code: String,
+    #[serde(default)]
+    city: String,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    #[serde(rename = "as", default)]
+    asn: String,// "AS15169 Google LLC", what IpInfo's free plan has as the org
+    #[serde(default)]
+    query: String,
+}
+
+impl Location
+{
+    fn details(self) -> Result<IpDetails, GeoError>
+    {
+        if self.status != "success"
+        {
+            return Err(format!("ip-api.com could not locate {}: {}", self.query, self.message).into());
+        }
+
+        let loc = match (self.lat, self.lon)
+        {
+            (Some(latitude), Some(longitude)) => format!("{},{}", latitude, longitude),
+            _ => String::new(),
+        };
+
+        Ok(IpDetails {
+            ip: self.query,
+            city: self.city,
+            country: self.country_code,
+            loc,
+            org: Some(self.asn).filter(|asn| !asn.is_empty()),
+            ..Default::default()
+        })
+    }
+}
+
+/// Lookups with the free ip-api.com service, which needs no token but sends the IPs to a third party.
+/// Requests are spaced evenly to stay within its rate limits. When it reports the limit as used up
+/// or refuses a request with a 429, every lookup waits until it resets and refused requests are sent again.
+pub struct IpApiProvider
+{
+    client: reqwest::Client,
+    requests: DefaultDirectRateLimiter,
+    batches: DefaultDirectRateLimiter,
+    blocked_until: Mutex<Option<Instant>>,// shared by single and batch lookups, the API counts them per IP
+}
+
+impl IpApiProvider
+{
+    pub fn new(timeout: Duration) -> Result<IpApiProvider, GeoError>
+    {
+        // no bursts, a burst of 45 right away would leave nothing for the rest of the minute the API counts
+        let per_minute = |count: u32| Quota::per_minute(NonZeroU32::new(count).unwrap()).allow_burst(NonZeroU32::new(1).unwrap());
+
+        Ok(IpApiProvider {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            requests: RateLimiter::direct(per_minute(REQUESTS_PER_MINUTE)),
+            batches: RateLimiter::direct(per_minute(BATCHES_PER_MINUTE)),
+            blocked_until: Mutex::new(None),
+        })
+    }
+
+    /// Blocks every lookup until the limit resets if the response used it up or was refused,
+    /// so the next requests aren't refused too
+    fn respect_limit(&self, response: &Response)
+    {
+        let header = |name: &str| response.headers().get(name)?.to_str().ok()?.parse::<u64>().ok();
+
+        // requests left in the current window and seconds until it resets
+        let reset = match (header("X-Rl"), header("X-Ttl"))
+        {
+            _ if response.status() == StatusCode::TOO_MANY_REQUESTS => header("X-Ttl").unwrap_or(RATE_LIMITED_PAUSE_SECS),
+            (Some(0), Some(reset)) => reset,
+            _ => return,
+        };
+
+        let until = Instant::now() + Duration::from_secs(reset);
+        let mut blocked_until = self.blocked_until.lock().unwrap();
+
+        // another response may already have blocked for longer
+        *blocked_until = Some(blocked_until.map_or(until, |blocked| blocked.max(until)));
+    }
+
+    /// Sends the request once the rate limiter and any block allow it, again after the block if it's refused with a 429
+    async fn send(&self, limiter: &DefaultDirectRateLimiter, request: impl Fn() -> RequestBuilder) -> reqwest::Result<Response>
+    {
+        let mut attempt = 0;
+
+        loop
+        {
+            limiter.until_ready().await;
+
+            let blocked_until = *self.blocked_until.lock().unwrap();
+
+            if let Some(until) = blocked_until
+            {
+                tokio::time::sleep_until(until).await;
+            }
+
+            let response = request().send().await?;
+            self.respect_limit(&response);
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS || attempt == RATE_LIMITED_RETRIES
+            {
+                return response.error_for_status();
+            }
+
+            warn!("ip-api.com rate limit reached, pausing lookups before trying again");
+            attempt += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl GeoProvider for IpApiProvider
+{
+    async fn query(&self, ip: &str) -> Result<IpDetails, GeoError>
+    {
+        let response = self.send(&self.requests, || {
+            self.client
+                .get(format!("{}/json/{}", API_URL, ip))
+                .query(&[("fields", FIELDS)])
+        }).await?;
+
+        let mut details = response.json::<Location>().await?.details()?;
+        details.ip = ip.to_string();
+
+        Ok(details)
+    }
+
+    async fn query_batch(&self, ips: &[&str]) -> HashMap<IP, IpDetails>
+    {
+        let mut found = HashMap::new();
+
+        for chunk in ips.chunks(BATCH_SIZE)
+        {
+            let response = self.send(&self.batches, || {
+                self.client
+                    .post(format!("{}/batch", API_URL))
+                    .query(&[("fields", FIELDS)])
+                    .json(chunk)
+            }).await;
+
+            let locations = match response
+            {
+                Ok(response) => response.json::<Vec<Location>>().await,
+                Err(err) => Err(err),
+            };
+
+            // answered in the order asked, "query" has the address a hostname resolved to instead of the hostname
+            match locations
+            {
+                Ok(locations) => {
+                    found.extend(chunk.iter()
+                        .zip(locations)
+                        .filter_map(|(ip, location)| {
+                            let details = IpDetails { ip: ip.to_string(), ..location.details().ok()? };
+                            Some((ip.to_string(), details))
+                        }));
+                }
+                Err(err) => { warn!("ip-api.com batch lookup of {} IPs failed, looking them up one by one: {}", chunk.len(), err); }
+            }
+        }
+
+        found
+    }
+}
@@ -1,49 +1,596 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
-use ipinfo::{IpDetails, IpError, IpInfo, IpInfoConfig};
-use crate::{IP, IPINFO_SECRET, IPINFO_TIMEOUT};
+use async_trait::async_trait;
+use log::warn;
+use ipinfo::{BatchReqOpts, IpDetails, IpError, IpErrorKind, IpInfo, IpInfoConfig};
+use tokio::time::Instant;
+use crate::IP;
+use crate::error::GeopingError;
+use crate::geo::{CacheStats, GeoError, GeoProvider};
+
+const BATCH_SIZE: usize = 100;// IPs per batch request
+const FAILURE_TTL: Duration = Duration::from_secs(3600);// how long a permanently failing IP isn't queried again
+
+/// How often a lookup is repeated on transient failures (timeouts, 5xx),
+/// waiting `base_delay` before the first retry and twice as long before every next one.
+/// Hitting the rate limit pauses all lookups for `rate_limit_pause` instead, as long as
+/// the pauses don't add up to more than `max_rate_limit_wait`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy
+{
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub rate_limit_pause: Duration,
+    pub max_rate_limit_wait: Duration,
+}
+
+/// Pauses shared by every query, so a rate limit hit by one stops all of them
+#[derive(Default)]
+struct RateLimit
+{
+    until: Option<Instant>,
+    waited: Duration,
+    exhausted: bool,
+}
+
+/// HTTP status the API answered with, None if it didn't respond
+fn http_status(err: &IpError) -> Option<u16>
+{
+    // "<kind>: <status> <reason>: ..." when the server responded, otherwise a connection problem
+    err.to_string()
+        .split(": ")
+        .nth(1)
+        .and_then(|desc| desc.get(..3))
+        .and_then(|code| code.parse::<u16>().ok())
+}
+
+fn is_transient(err: &IpError) -> bool
+{
+    match err.kind()
+    {
+        IpErrorKind::TimeOutError => true,
+        IpErrorKind::HTTPClientError => {
+            match http_status(err)
+            {
+                Some(code) => code == 408 || code == 429 || code >= 500,
+                None => true,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Failures about the IP itself, which asking again would only repeat: the API refusing it
+/// in its response, or a 404 ("Wrong ip"). A bad token (401, 403) or a response which doesn't
+/// parse is about the request, so it isn't one of them.
+fn is_about_the_ip(err: &IpError) -> bool
+{
+    match err.kind()
+    {
+        IpErrorKind::IpRequestError => true,
+        IpErrorKind::HTTPClientError => http_status(err) == Some(404),
+        _ => false,
+    }
+}
+
+/// The requests [`IpInfoClientWrapper`] sends, the ipinfo client or a stand-in for it
+#[async_trait]
+pub trait IpLookup: Send
+{
+    async fn lookup(&mut self, ip: &str) -> Result<IpDetails, IpError>;
+
+    async fn lookup_batch(&mut self, ips: &[&str]) -> Result<HashMap<IP, IpDetails>, IpError>;
+}
+
+#[async_trait]
+impl IpLookup for IpInfo
+{
+    async fn lookup(&mut self, ip: &str) -> Result<IpDetails, IpError>
+    {
+        IpInfo::lookup(self, ip).await
+    }
+
+    async fn lookup_batch(&mut self, ips: &[&str]) -> Result<HashMap<IP, IpDetails>, IpError>
+    {
+        IpInfo::lookup_batch(self, ips, BatchReqOpts::default()).await
+    }
+}
+
+/// Lookup which failed for good, e.g. an IP the API doesn't know - kept in memory only,
+/// so the next run or an --interval scan after `FAILURE_TTL` tries again
+struct Failure
+{
+    at: Instant,
+    kind: IpErrorKind,
+    description: String,
+}
+
+impl Failure
+{
+    fn new(err: &IpError) -> Failure
+    {
+        // IpError doesn't expose its description apart from "<kind>: <description>"
+        let message = err.to_string();
+        let description = message.strip_prefix(err.kind().as_str()).unwrap_or(message.as_str()).trim_start_matches(": ");
+
+        Failure { at: Instant::now(), kind: err.kind(), description: description.to_string() }
+    }
+
+    fn error(&self) -> IpError
+    {
+        IpError::new(self.kind, Some(format!("{} (cached failure)", self.description).as_str()))
+    }
+}
 
 pub struct IpInfoClientWrapper
 {
-    native_client: IpInfo,
-    cache: HashMap<IP, IpDetails>,
+    // IpInfo::lookup borrows the client mutably for the whole request,
+    // so concurrent queries are spread over a pool of clients
+    native_clients: Vec<tokio::sync::Mutex<Box<dyn IpLookup>>>,
+    next_client: AtomicUsize,
+    cache: Mutex<HashMap<IP, IpDetails>>,
+    cache_path: PathBuf,
+    failures: Mutex<HashMap<IP, Failure>>,
+    retry: RetryPolicy,
+    rate_limit: Mutex<RateLimit>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    requests: AtomicU64,
+    max_requests: Option<u64>,// over the client's lifetime, nothing is looked up any more once they're sent
+    max_requests_reached: AtomicBool,
 }
 
 impl IpInfoClientWrapper
 {
-    pub fn new(secret: &str, query_timeout: Duration) -> Result<IpInfoClientWrapper, IpError>
+    /// Loads previously resolved IPs from `cache_path` if the file exists,
+    /// `concurrency` is the number of queries which can be in flight at once.
+    /// Once `max_requests` were sent, lookups of uncached IPs fail.
+    pub fn new(secret: &str, query_timeout: Duration, cache_path: &Path, concurrency: usize, retry: RetryPolicy, max_requests: Option<u64>) -> Result<IpInfoClientWrapper, GeopingError>
+    {
+        if secret.trim().is_empty()
+        {
+            return Err(IpError::new(IpErrorKind::IpRequestError, Some("IpInfo token is empty")).into());
+        }
+
+        let mut native_clients: Vec<Box<dyn IpLookup>> = vec![];
+
+        for _ in 0..concurrency.max(1)
+        {
+            let ipcfg = IpInfoConfig {
+                token: Some(secret.to_string()),
+                timeout: query_timeout,
+                ..Default::default()
+            };
+
+            native_clients.push(Box::new(IpInfo::new(ipcfg)?));
+        }
+
+        Ok(IpInfoClientWrapper::with_clients(native_clients, cache_path, retry, max_requests))
+    }
+
+    /// Like [`IpInfoClientWrapper::new`], but sending its lookups through `native_clients`, one query at a time each
+    pub fn with_clients(native_clients: Vec<Box<dyn IpLookup>>, cache_path: &Path, retry: RetryPolicy, max_requests: Option<u64>) -> IpInfoClientWrapper
+    {
+        assert!(!native_clients.is_empty(), "at least one client is needed");
+
+        IpInfoClientWrapper {
+            native_clients: native_clients.into_iter().map(tokio::sync::Mutex::new).collect(),
+            next_client: AtomicUsize::new(0),
+            cache: Mutex::new(Self::load_cache(cache_path)),
+            cache_path: cache_path.to_path_buf(),
+            failures: Mutex::new(HashMap::new()),
+            retry,
+            rate_limit: Mutex::new(RateLimit::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            max_requests,
+            max_requests_reached: AtomicBool::new(false),
+        }
+    }
+
+    fn load_cache(path: &Path) -> HashMap<IP, IpDetails>
     {
-        let ipcfg = IpInfoConfig {
-            token: Some(secret.to_string()),
-            timeout: query_timeout,
-            ..Default::default()
+        match fs::read_to_string(path)
+        {
+            Ok(content) => {
+                match serde_json::from_str(content.as_str())
+                {
+                    Ok(cache) => cache,
+                    Err(err) => {
+                        warn!("Ignoring IpInfo cache {}: {}", path.to_string_lossy(), err);
+                        HashMap::new()
+                    }
+                }
+            }
+            Err(_) => { /* no cache yet */ HashMap::new() }
+        }
+    }
+
+    /// Writes every resolved IP to the cache file so the next run doesn't query them again
+    pub fn flush(&self) -> io::Result<()>
+    {
+        let content = serde_json::to_string(&*self.cache.lock().unwrap())?;
+
+        fs::write(&self.cache_path, content)
+    }
+
+    /// Waits out the rate limit, false once the pauses would exceed the max wait.
+    /// The ipinfo crate doesn't pass Retry-After on, so every pause has the same length.
+    async fn pause_for_rate_limit(&self) -> bool
+    {
+        let until = {
+            let mut rate_limit = self.rate_limit.lock().unwrap();
+            let now = Instant::now();
+
+            match rate_limit.until
+            {
+                // another query is already pausing
+                Some(until) if until > now => until,
+                _ if rate_limit.exhausted => return false,
+                _ if rate_limit.waited + self.retry.rate_limit_pause > self.retry.max_rate_limit_wait => {
+                    warn!("IpInfo rate limit still reached after pausing {}s, giving up on the remaining lookups", rate_limit.waited.as_secs());
+                    rate_limit.exhausted = true;
+                    return false;
+                }
+                _ => {
+                    rate_limit.waited += self.retry.rate_limit_pause;
+                    rate_limit.until = Some(now + self.retry.rate_limit_pause);
+
+                    warn!("IpInfo rate limit reached, pausing lookups for {}s ({}s of at most {}s so far)",
+                        self.retry.rate_limit_pause.as_secs(), rate_limit.waited.as_secs(), self.retry.max_rate_limit_wait.as_secs());
+
+                    now + self.retry.rate_limit_pause
+                }
+            }
         };
 
-        Ok(IpInfoClientWrapper {
-            native_client: IpInfo::new(ipcfg)?,
-            cache: HashMap::new()
-        })
+        tokio::time::sleep_until(until).await;
+
+        true
+    }
+
+    /// Counts a request about to be sent, false once `max_requests` were
+    fn take_request(&self) -> bool
+    {
+        let taken = self.requests
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sent| match self.max_requests
+            {
+                Some(max) if sent >= max => None,
+                _ => Some(sent + 1),
+            })
+            .is_ok();
+
+        if !taken && !self.max_requests_reached.swap(true, Ordering::Relaxed)
+        {
+            warn!("Sent all {} IpInfo requests allowed by --max-requests, the remaining servers are left uncorrected", self.max_requests.unwrap_or_default());
+        }
+
+        taken
     }
 
-    pub async fn query(&mut self, ip: &str) -> Result<IpDetails, IpError>
+    /// Error of an earlier lookup of `ip` which failed for good less than `FAILURE_TTL` ago
+    fn cached_failure(&self, ip: &str) -> Option<IpError>
     {
-        if let Some(details) = self.cache.get(&ip.to_string())
+        let mut failures = self.failures.lock().unwrap();
+
+        match failures.get(ip)
         {
-            Ok(details.clone())
+            Some(failure) if failure.at.elapsed() < FAILURE_TTL => Some(failure.error()),
+            Some(_) => { failures.remove(ip); None }
+            None => None,
+        }
+    }
+
+    /// Cached lookups, successful or failed for good, don't reach the network.
+    /// Only failures about the IP itself are cached, not timeouts, 5xx, the rate limit or a bad token.
+    pub async fn query(&self, ip: &str) -> Result<IpDetails, IpError>
+    {
+        let cached = self.cache.lock().unwrap().get(ip).cloned();
+
+        if let Some(details) = cached
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            Ok(details)
+        }
+        else if let Some(err) = self.cached_failure(ip)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            Err(err)
         }
         else
         {
-            match self.native_client.lookup(ip).await
+            self.misses.fetch_add(1, Ordering::Relaxed);
+
+            let i = self.next_client.fetch_add(1, Ordering::Relaxed) % self.native_clients.len();
+            let mut native_client = self.native_clients[i].lock().await;
+
+            let mut attempt = 0;
+
+            loop
+            {
+                if !self.take_request()
+                {
+                    return Err(IpError::new(IpErrorKind::IpRequestError, Some("--max-requests reached")));
+                }
+
+                match native_client.lookup(ip).await
+                {
+                    Ok(details) => {
+
+                        self.cache.lock().unwrap().insert(ip.to_string(), details.clone());
+                        return Ok(details);
+
+                    }
+                    Err(err) if err.kind() == IpErrorKind::RateLimitExceededError => {
+                        if !self.pause_for_rate_limit().await { return Err(err); }
+                    }
+                    Err(err) if attempt < self.retry.retries && is_transient(&err) => {
+                        tokio::time::sleep(self.retry.base_delay * 2u32.pow(attempt)).await;
+                        attempt += 1;
+                    }
+                    Err(err) => {
+                        if is_about_the_ip(&err)
+                        {
+                            self.failures.lock().unwrap().insert(ip.to_string(), Failure::new(&err));
+                        }
+
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl IpInfoClientWrapper
+{
+    /// IPs answered from the cache and looked up over the network, and requests sent, since the client was created
+    pub fn cache_stats(&self) -> CacheStats
+    {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resolves uncached IPs with the batch endpoint, `BATCH_SIZE` per request. IPs of
+    /// a failed batch are left out of the result, so they can still be queried one by one.
+    pub async fn query_batch(&self, ips: &[&str]) -> HashMap<IP, IpDetails>
+    {
+        let mut found = HashMap::new();
+        let mut misses = vec![];
+
+        {
+            let cache = self.cache.lock().unwrap();
+
+            for ip in ips
+            {
+                match cache.get(*ip)
+                {
+                    Some(details) => {
+                        found.insert(ip.to_string(), details.clone());
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                    }
+                    // known failures are answered from the cache by query
+                    None if self.cached_failure(ip).is_some() => {}
+                    None => { misses.push(*ip); }
+                }
+            }
+        }
+
+        let i = self.next_client.fetch_add(1, Ordering::Relaxed) % self.native_clients.len();
+        let mut native_client = self.native_clients[i].lock().await;
+
+        for chunk in misses.chunks(BATCH_SIZE)
+        {
+            if !self.take_request() { break; }
+
+            match native_client.lookup_batch(chunk).await
             {
                 Ok(details) => {
+                    // a failed batch is looked up again one by one, only count it once then
+                    self.misses.fetch_add(chunk.len() as u64, Ordering::Relaxed);
 
-                    self.cache.insert(ip.to_string(), details.clone());
-                    Ok(details)
+                    let mut cache = self.cache.lock().unwrap();
 
+                    for (ip, details) in details
+                    {
+                        cache.insert(ip.clone(), details.clone());
+                        found.insert(ip, details);
+                    }
                 }
-                Err(err) => { Err(err) }
+                Err(err) => { warn!("IpInfo batch lookup of {} IPs failed, looking them up one by one: {}", chunk.len(), err); }
+            }
+        }
+
+        found
+    }
+}
+
+#[async_trait]
+impl GeoProvider for IpInfoClientWrapper
+{
+    async fn query(&self, ip: &str) -> Result<IpDetails, GeoError>
+    {
+        Ok(IpInfoClientWrapper::query(self, ip).await?)
+    }
+
+    async fn query_batch(&self, ips: &[&str]) -> HashMap<IP, IpDetails>
+    {
+        IpInfoClientWrapper::query_batch(self, ips).await
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats>
+    {
+        Some(IpInfoClientWrapper::cache_stats(self))
+    }
+
+    fn flush(&self) -> io::Result<()>
+    {
+        IpInfoClientWrapper::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::Arc;
+    use super::*;
+
+    /// Fails with `errors` in turn, then places every IP in DE, batches always fail
+    struct ScriptedLookup
+    {
+        errors: Vec<(IpErrorKind, &'static str)>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl IpLookup for ScriptedLookup
+    {
+        async fn lookup(&mut self, ip: &str) -> Result<IpDetails, IpError>
+        {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+
+            if self.errors.is_empty() { Ok(IpDetails { ip: ip.to_string(), country: "DE".to_string(), ..Default::default() }) }
+            else
+            {
+                let (kind, description) = self.errors.remove(0);
+                Err(IpError::new(kind, Some(description)))
             }
         }
+
+        async fn lookup_batch(&mut self, _ips: &[&str]) -> Result<HashMap<IP, IpDetails>, IpError>
+        {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+
+            Err(IpError::new(IpErrorKind::IpRequestError, Some("no batches")))
+        }
+    }
+
+    /// Client failing with `errors` first, along with the count of lookups it gets
+    fn client(errors: &[IpErrorKind]) -> (IpInfoClientWrapper, Arc<AtomicUsize>)
+    {
+        let errors: Vec<(IpErrorKind, &str)> = errors.iter().map(|kind| (*kind, "scripted")).collect();
+
+        client_failing_with(&errors)
+    }
+
+    /// Like [`client`], with the description of every error too
+    fn client_failing_with(errors: &[(IpErrorKind, &'static str)]) -> (IpInfoClientWrapper, Arc<AtomicUsize>)
+    {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lookup = ScriptedLookup { errors: errors.to_vec(), calls: calls.clone() };
+
+        (IpInfoClientWrapper::with_clients(vec![Box::new(lookup)], &no_cache(), retry(), None), calls)
+    }
+
+    fn retry() -> RetryPolicy
+    {
+        RetryPolicy { retries: 2, base_delay: Duration::ZERO, rate_limit_pause: Duration::ZERO, max_rate_limit_wait: Duration::ZERO }
+    }
+
+    fn no_cache() -> PathBuf
+    {
+        std::env::temp_dir().join("geoping-test-no-such-cache.json")
+    }
+
+    #[test]
+    fn empty_token_is_refused()
+    {
+        for token in ["", "  "]
+        {
+            assert!(IpInfoClientWrapper::new(token, Duration::from_secs(1), &no_cache(), 1, retry(), None).is_err(), "{:?} was accepted", token);
+        }
+
+        assert!(IpInfoClientWrapper::new("token", Duration::from_secs(1), &no_cache(), 1, retry(), None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures()
+    {
+        let (client, calls) = client(&[IpErrorKind::TimeOutError, IpErrorKind::TimeOutError]);
+
+        let details = client.query("192.0.2.1").await.unwrap();
+
+        assert_eq!(details.country, "DE");
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_the_retries_are_used()
+    {
+        let (client, calls) = client(&[IpErrorKind::TimeOutError; 3]);
+
+        let err = client.query("192.0.2.1").await.unwrap_err();
+
+        assert_eq!(err.kind(), IpErrorKind::TimeOutError);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    // as the ipinfo crate words them, "<status>: <reqwest error>"
+    const NOT_FOUND: &str = "404 Not Found: HTTP status client error (404 Not Found) for url (https://ipinfo.io/192.0.2.300)";
+    const FORBIDDEN: &str = "403 Forbidden: HTTP status client error (403 Forbidden) for url (https://ipinfo.io/192.0.2.1)";
+
+    #[tokio::test]
+    async fn ip_the_api_rejects_reaches_the_network_once()
+    {
+        let (client, calls) = client_failing_with(&[(IpErrorKind::HTTPClientError, NOT_FOUND)]);
+
+        let first = client.query("192.0.2.300").await.unwrap_err();
+        let second = client.query("192.0.2.300").await.unwrap_err();
+
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(second.kind(), first.kind());
+        assert!(second.to_string().contains("404 Not Found") && second.to_string().contains("cached failure"), "{}", second);
+        assert_eq!(client.cache_stats(), CacheStats { hits: 1, misses: 1, requests: 1 });
+
+        // batches skip it too
+        assert!(client.query_batch(&["192.0.2.300"]).await.is_empty());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn failures_of_the_request_are_not_cached()
+    {
+        // a token without access is about every IP, it may well be fixed before the next scan
+        let (client, calls) = client_failing_with(&[(IpErrorKind::HTTPClientError, FORBIDDEN), (IpErrorKind::ParseError, "expected value")]);
+
+        assert!(client.query("192.0.2.1").await.is_err());
+        assert!(client.query("192.0.2.1").await.is_err());
+        assert_eq!(client.query("192.0.2.1").await.unwrap().country, "DE");
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_not_cached()
+    {
+        let (client, calls) = client(&[IpErrorKind::TimeOutError; 3]);
+
+        assert!(client.query("192.0.2.1").await.is_err());
+        assert_eq!(client.query("192.0.2.1").await.unwrap().country, "DE");
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn ips_refused_by_max_requests_are_not_cached()
+    {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lookup = ScriptedLookup { errors: vec![], calls: calls.clone() };
+        let client = IpInfoClientWrapper::with_clients(vec![Box::new(lookup)], &no_cache(), retry(), Some(1));
+
+        assert!(client.query("192.0.2.1").await.is_ok());
+        assert!(client.query("192.0.2.2").await.unwrap_err().to_string().contains("--max-requests"));
+
+        // a refusal says nothing about the IP, a later run or a raised cap still looks it up
+        assert!(client.cached_failure("192.0.2.2").is_none());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(client.cache_stats().requests, 1);
+    }
+}
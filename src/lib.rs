@@ -0,0 +1,35 @@
+//! Pings DNS servers listed per country and aggregates their latencies.
+//!
+//! The pipeline is [`collect_servers`] -> [`ping_servers`] -> optionally [`enrich_locations`]
+//! -> [`generate_csv`] or [`generate_json`].
+
+pub mod error;
+pub mod geo;
+pub mod inventory;
+pub mod ipapi;
+pub mod iplookup;
+pub mod lint;
+pub mod locate;
+pub mod merge;
+pub mod output;
+pub mod partial;
+pub mod ping;
+pub mod progress;
+pub mod sqlite;
+
+pub use error::GeopingError;
+pub use geo::{CacheStats, FallbackProvider, GeoProvider, MaxMindProvider};
+pub use ipapi::IpApiProvider;
+pub use inventory::{collect_servers, filter_cities, filter_countries, load_combined, sample_servers, Concurrency, Diagnostic, EntryFields, Servers};
+pub use lint::{format_lint_report, lint_report_json, lint_servers, LintIssue};
+pub use locate::{enrich_locations, CountryCorrection};
+pub use merge::merge_results;
+pub use output::{aggregate, check_sample_sizes, country_stats, format_ranking, format_server_table, generate_csv, generate_detailed_csv, generate_geojson, generate_html, generate_json, generate_metadata, generate_ndjson, generate_prometheus, generate_unreachable_csv, keep_top, ndjson_lines, write_output, Compression, CountryStats, OutputFormat, OutputStyle, RunMetadata, ServerMetric};
+pub use partial::{load_partial, PartialWriter};
+pub use ping::{open_probe, ping_servers, Fallback, IcmpProbe, IpVersion, Measurement, PingOptions, PingSummary, Probe, ProbeContext, ProbeKind, Replies, TcpProbe};
+pub use sqlite::write_sqlite;
+
+pub type CountryCode = String;// like DE, PL
+pub type City = String;
+pub type IP = String;// either v4 or v6
+pub type Rtt = f64;// Round Trip Time / latency / ping time
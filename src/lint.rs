@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use serde_json::{json, Value};
+use crate::{City, CountryCode, IP};
+use crate::inventory::{iso_country_code, Diagnostic, Servers};
+
+/// Problem with a server which can be loaded, but likely shouldn't be listed as it is
+#[derive(Debug, Clone)]
+pub struct LintIssue
+{
+    pub country: CountryCode,
+    pub city: City,
+    pub ip: IP,
+    pub problem: String,
+}
+
+/// Why an address shouldn't be pinged from the internet, None for public ones
+fn reserved_range(addr: IpAddr) -> Option<&'static str>
+{
+    match addr
+    {
+        IpAddr::V4(addr) if addr.is_private() => Some("private"),
+        IpAddr::V4(addr) if addr.is_link_local() => Some("link-local"),
+        IpAddr::V4(addr) if addr.is_documentation() => Some("documentation"),
+        IpAddr::V4(addr) if addr.is_broadcast() => Some("broadcast"),
+        IpAddr::V4(addr) if addr.octets()[0] == 100 && addr.octets()[1] & 0xc0 == 64 => Some("shared (CGNAT)"),
+        IpAddr::V4(addr) if addr.octets()[0] >= 240 => Some("reserved"),
+        IpAddr::V6(addr) if addr.segments()[0] & 0xfe00 == 0xfc00 => Some("unique local"),
+        IpAddr::V6(addr) if addr.segments()[0] & 0xffc0 == 0xfe80 => Some("link-local"),
+        IpAddr::V6(addr) if addr.segments()[0] == 0x2001 && addr.segments()[1] == 0xdb8 => Some("documentation"),
+        addr if addr.is_loopback() => Some("loopback"),
+        addr if addr.is_unspecified() => Some("unspecified"),
+        addr if addr.is_multicast() => Some("multicast"),
+        _ => None,
+    }
+}
+
+/// Hostnames are accepted in place of IPs, internationalized ones too, anything else which doesn't parse is a typo
+fn is_hostname(host: &str) -> bool
+{
+    let Ok(host) = idna::domain_to_ascii(host) else { return false; };
+    let host = host.strip_suffix('.').unwrap_or(&host);
+
+    !host.is_empty() && host.len() <= 253 && host.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63
+            && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+    // "1.2.3" is a broken IP rather than a hostname
+    && !host.split('.').all(|label| label.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Country codes are named after the file, `de.json` or a region of a country like `us-east.json`
+fn is_country_code(cc: &str) -> bool
+{
+    let country = iso_country_code(cc);
+
+    country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+/// Checks loaded servers for what keeps results from being trustworthy: IPs listed more than once
+/// (each one is pinged again, unless --dedupe), private or reserved addresses, servers without a city,
+/// IPs which are neither an address nor a hostname and files not named after a country code.
+pub fn lint_servers(servers: &Servers) -> Vec<LintIssue>
+{
+    let mut issues = vec![];
+
+    let mut countries: Vec<(&CountryCode, &Vec<(City, IP)>)> = servers.iter().collect();
+    countries.sort_by_key(|(cc, _)| *cc);
+
+    let mut seen: HashMap<&str, (&CountryCode, &City)> = HashMap::new();
+
+    for (cc, cities) in countries
+    {
+        let issue = |city: &City, ip: &IP, problem: String| LintIssue { country: cc.clone(), city: city.clone(), ip: ip.clone(), problem };
+
+        if !is_country_code(cc)
+        {
+            issues.push(issue(&String::new(), &String::new(), "file isn't named after a two-letter country code".to_string()));
+        }
+
+        for (city, ip) in cities
+        {
+            let host = ip.trim();
+
+            match host.parse::<IpAddr>()
+            {
+                Ok(addr) => {
+                    if let Some(range) = reserved_range(addr)
+                    {
+                        issues.push(issue(city, ip, format!("{} address", range)));
+                    }
+                }
+                Err(_) if !is_hostname(host) => { issues.push(issue(city, ip, "neither an IP nor a hostname".to_string())); }
+                Err(_) => {}
+            }
+
+            if city.trim().is_empty()
+            {
+                issues.push(issue(city, ip, "no city".to_string()));
+            }
+
+            match seen.get(host)
+            {
+                Some((first_cc, first_city)) => { issues.push(issue(city, ip, format!("already listed under {} {}", first_cc, first_city))); }
+                None => { seen.insert(host, (cc, city)); }
+            }
+        }
+    }
+
+    issues
+}
+
+/// Problems of the server files and their servers for the console, one per line
+pub fn format_lint_report(diagnostics: &[Diagnostic], issues: &[LintIssue]) -> String
+{
+    let mut report = String::new();
+
+    for diagnostic in diagnostics
+    {
+        let file = diagnostic.path.to_string_lossy();
+
+        match diagnostic.entry.as_str()
+        {
+            "" => { report += format!("{}: {}\n", file, diagnostic.problem).as_str(); }
+            entry => { report += format!("{} {}: {}\n", file, entry, diagnostic.problem).as_str(); }
+        }
+    }
+
+    for issue in issues
+    {
+        let server: Vec<&str> = [&issue.country, &issue.city, &issue.ip].into_iter()
+            .map(|field| field.as_str())
+            .filter(|field| !field.trim().is_empty())
+            .collect();
+
+        report += format!("{}: {}\n", server.join(" "), issue.problem).as_str();
+    }
+
+    report += format!("{} problems\n", diagnostics.len() + issues.len()).as_str();
+
+    report
+}
+
+/// Same as [`format_lint_report`] for CI, `{ "files": [...], "servers": [...], "problems": N }`
+pub fn lint_report_json(diagnostics: &[Diagnostic], issues: &[LintIssue]) -> String
+{
+    let files: Vec<Value> = diagnostics.iter()
+        .map(|diagnostic| json!({ "path": diagnostic.path, "entry": diagnostic.entry, "problem": diagnostic.problem }))
+        .collect();
+
+    let servers: Vec<Value> = issues.iter()
+        .map(|issue| json!({ "country": issue.country, "city": issue.city, "ip": issue.ip, "problem": issue.problem }))
+        .collect();
+
+    serde_json::to_string_pretty(&json!({
+        "files": files,
+        "servers": servers,
+        "problems": diagnostics.len() + issues.len(),
+    })).unwrap()
+}
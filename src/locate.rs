@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use clap::ValueEnum;
+use futures::{stream, StreamExt};
+use ipinfo::IpDetails;
+use log::{info, warn};
+use crate::{CountryCode, IP};
+use crate::geo::GeoProvider;
+use crate::inventory::iso_country_code;
+use crate::ping::Measurement;
+use crate::progress;
+use crate::progress::Verbosity;
+
+/// What [`enrich_locations`] does with servers the provider places in another country
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CountryCorrection
+{
+    /// Don't look servers up at all
+    Off,
+    /// Only fill in missing cities, the countries of the server files are taken as the truth
+    Fill,
+    /// Only log the mismatches, providers sometimes place anycast blocks in their registrant's country
+    Warn,
+    /// Move servers to the country the provider places them in
+    Move,
+}
+
+/// Looks every IP up once and uses the result for all servers with it: servers listed without
+/// a city get the provider's one, and so do the organization hosting them and their coordinates if they're known.
+/// With [`CountryCorrection::Move`] servers are moved to the country the provider places them in,
+/// servers of countries which aren't in `rtts` at all are dropped. With [`CountryCorrection::Warn`]
+/// the mismatches are only logged, with [`CountryCorrection::Fill`] they aren't even looked for
+/// and only the servers without a city are looked up. Servers of region keys like `US-EAST` are in place within `US`.
+pub async fn enrich_locations(rtts: &mut HashMap<CountryCode, Vec<Measurement>>, provider: &dyn GeoProvider, concurrency: usize, verbosity: Verbosity, mode: CountryCorrection)
+{
+    if mode == CountryCorrection::Off { return; }
+
+    info!("Looking up locations...");
+
+    // every IP is looked up once, even if listed in a few countries
+    let mut ips: Vec<IP> = rtts.values()
+        .flatten()
+        .filter(|server| mode != CountryCorrection::Fill || server.city.trim().is_empty())
+        .map(|server| server.ip.clone())
+        .collect();
+    ips.sort();
+    ips.dedup();
+
+    let bar = progress::bar(ips.len() as u64, "Lookups", verbosity);
+
+    let ip_refs: Vec<&str> = ips.iter().map(|ip| ip.as_str()).collect();
+    let batched = provider.query_batch(&ip_refs).await;
+
+    // whatever the batch didn't resolve is looked up one IP at a time
+    let missing: Vec<IP> = ips.iter().filter(|ip| !batched.contains_key(*ip)).cloned().collect();
+
+    let mut lookups = stream::iter(missing)
+        .map(|ip| async move {
+            let details = provider.query(ip.as_str()).await;
+            (ip, details)
+        })
+        .buffer_unordered(concurrency);
+
+    let mut countries = HashMap::new();
+    let mut orgs = HashMap::new();
+    let mut cities = HashMap::new();
+    let mut locations = HashMap::new();
+
+    let mut record = |ip: IP, details: IpDetails| {
+        // paid plans have the ASN separately, the free ones put it in front of the org name
+        let org = details.org.or(details.asn.map(|asn| asn.asn));
+        if let Some(org) = org { orgs.insert(ip.clone(), org); }
+
+        if !details.city.is_empty() { cities.insert(ip.clone(), details.city); }
+
+        // "52.5244,13.4105"
+        if let Some((latitude, longitude)) = details.loc.split_once(',')
+        {
+            if let (Ok(latitude), Ok(longitude)) = (latitude.trim().parse(), longitude.trim().parse())
+            {
+                locations.insert(ip.clone(), (latitude, longitude));
+            }
+        }
+
+        let country = details.country;
+
+        if country.is_empty() { bar.suspend(|| warn!("Unknown country for IP {}", ip)); }
+        else { countries.insert(ip, country); }
+
+        bar.inc(1);
+    };
+
+    for (ip, details) in batched
+    {
+        record(ip, details);
+    }
+
+    while let Some((ip, details)) = lookups.next().await
+    {
+        match details
+        {
+            Ok(details) => { record(ip, details); }
+            Err(err) => {
+                bar.suspend(|| warn!("Could not resolve country for IP {}: {}", ip, err));
+                bar.inc(1);
+            }
+        }
+    }
+
+    bar.finish();
+
+    for server in rtts.values_mut().flatten()
+    {
+        server.asn = orgs.get(&server.ip).cloned();
+        server.location = locations.get(&server.ip).cloned();
+
+        if server.city.trim().is_empty()
+        {
+            if let Some(city) = cities.get(&server.ip) { server.city = city.clone(); }
+        }
+    }
+
+    if mode == CountryCorrection::Fill { return; }
+
+    if mode == CountryCorrection::Warn
+    {
+        let mut mismatches = 0;
+
+        for (cc, cities) in rtts.iter()
+        {
+            for server in cities.iter().filter(|server| countries.get(&server.ip).is_some_and(|country| country != iso_country_code(cc)))
+            {
+                warn!("{} {} {} is placed in {} by the provider, keeping it", cc, server.city, server.ip, countries[&server.ip]);
+                mismatches += 1;
+            }
+        }
+
+        if mismatches > 0
+        {
+            warn!("{} servers may be listed under the wrong country, run with --country-correction move to move them", mismatches);
+        }
+
+        return;
+    }
+
+    // nothing is moved until all lookups are done
+
+    let mut moved = vec![];
+
+    for (cc, cities) in rtts.iter_mut()
+    {
+        let (stay, go): (Vec<_>, Vec<_>) = cities
+            .drain(..)
+            .partition(|server| countries.get(&server.ip).is_none_or(|country| country == iso_country_code(cc)));
+
+        *cities = stay;
+        moved.extend(go.into_iter().map(|server| (countries[&server.ip].clone(), server)));
+    }
+
+    for (cc_real, server) in moved
+    {
+        if let Some(entry) = rtts.get_mut(&cc_real) {
+            entry.push(server);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use ipinfo::IpDetails;
+    use crate::geo::GeoError;
+    use crate::ping::ProbeKind;
+    use super::*;
+
+    /// Places every IP it knows in a country and city, and remembers what it was asked
+    struct FakeProvider
+    {
+        places: HashMap<IP, (CountryCode, String)>,
+        queried: Mutex<Vec<IP>>,
+    }
+
+    #[async_trait]
+    impl GeoProvider for FakeProvider
+    {
+        async fn query(&self, ip: &str) -> Result<IpDetails, GeoError>
+        {
+            self.queried.lock().unwrap().push(ip.to_string());
+
+            let (country, city) = self.places.get(ip).cloned().ok_or("unknown IP")?;
+
+            Ok(IpDetails { ip: ip.to_string(), country, city, ..Default::default() })
+        }
+    }
+
+    fn provider(places: &[(&str, &str, &str)]) -> FakeProvider
+    {
+        FakeProvider {
+            places: places.iter().map(|(ip, country, city)| (ip.to_string(), (country.to_string(), city.to_string()))).collect(),
+            queried: Mutex::new(vec![]),
+        }
+    }
+
+    fn server(city: &str, ip: &str) -> Measurement
+    {
+        Measurement {
+            city: city.to_string(),
+            ip: ip.to_string(),
+            rtt: 10.0,
+            samples: vec![10.0],
+            sent: 1,
+            ttl: None,
+            asn: None,
+            location: None,
+            above_threshold: false,
+            probe: ProbeKind::Icmp,
+        }
+    }
+
+    fn rtts(countries: &[(&str, Vec<Measurement>)]) -> HashMap<CountryCode, Vec<Measurement>>
+    {
+        countries.iter().map(|(cc, servers)| (cc.to_string(), servers.clone())).collect()
+    }
+
+    #[tokio::test]
+    async fn move_takes_misassigned_servers_to_their_country()
+    {
+        let provider = provider(&[
+            ("192.0.2.1", "DE", "Berlin"),
+            ("192.0.2.2", "PL", "Warsaw"),
+            ("198.51.100.1", "PL", "Krakow"),
+            ("198.51.100.2", "DE", "Munich"),
+        ]);
+        let mut rtts = rtts(&[
+            ("DE", vec![server("Berlin", "192.0.2.1"), server("Warsaw", "192.0.2.2")]),
+            ("PL", vec![server("Krakow", "198.51.100.1"), server("Munich", "198.51.100.2")]),
+        ]);
+
+        enrich_locations(&mut rtts, &provider, 2, Verbosity::Quiet, CountryCorrection::Move).await;
+
+        let ips = |cc: &str| {
+            let mut ips: Vec<&str> = rtts[cc].iter().map(|server| server.ip.as_str()).collect();
+            ips.sort();
+            ips
+        };
+
+        assert_eq!(ips("DE"), ["192.0.2.1", "198.51.100.2"]);
+        assert_eq!(ips("PL"), ["192.0.2.2", "198.51.100.1"]);
+        assert_eq!(provider.queried.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn warn_leaves_misassigned_servers_in_place()
+    {
+        let provider = provider(&[("192.0.2.1", "DE", "Berlin"), ("192.0.2.2", "PL", "Warsaw")]);
+        let mut rtts = rtts(&[("DE", vec![server("Berlin", "192.0.2.1"), server("Warsaw", "192.0.2.2")]), ("PL", vec![])]);
+
+        enrich_locations(&mut rtts, &provider, 2, Verbosity::Quiet, CountryCorrection::Warn).await;
+
+        assert_eq!(rtts["DE"].len(), 2);
+        assert!(rtts["PL"].is_empty());
+
+        enrich_locations(&mut rtts, &provider, 2, Verbosity::Quiet, CountryCorrection::Off).await;
+
+        // off doesn't even look them up
+        assert_eq!(provider.queried.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn region_files_keep_the_servers_of_their_country()
+    {
+        let provider = provider(&[("192.0.2.1", "US", "Ashburn"), ("192.0.2.2", "FR", "Paris")]);
+        let mut rtts = rtts(&[("US-EAST", vec![server("Ashburn", "192.0.2.1"), server("Paris", "192.0.2.2")]), ("FR", vec![])]);
+
+        enrich_locations(&mut rtts, &provider, 2, Verbosity::Quiet, CountryCorrection::Move).await;
+
+        assert_eq!(rtts["US-EAST"].iter().map(|server| server.ip.as_str()).collect::<Vec<_>>(), ["192.0.2.1"]);
+        assert_eq!(rtts["FR"].iter().map(|server| server.ip.as_str()).collect::<Vec<_>>(), ["192.0.2.2"]);
+    }
+
+    #[tokio::test]
+    async fn fill_only_looks_up_servers_without_a_city()
+    {
+        let provider = provider(&[("192.0.2.1", "DE", "Frankfurt"), ("192.0.2.2", "PL", "Warsaw")]);
+        let mut rtts = rtts(&[("DE", vec![server("Berlin", "192.0.2.1"), server(" ", "192.0.2.2")])]);
+
+        enrich_locations(&mut rtts, &provider, 1, Verbosity::Quiet, CountryCorrection::Fill).await;
+
+        let cities: Vec<&str> = rtts["DE"].iter().map(|server| server.city.as_str()).collect();
+
+        assert_eq!(cities, ["Berlin", "Warsaw"]);
+        assert_eq!(rtts.len(), 1);
+        assert_eq!(*provider.queried.lock().unwrap(), ["192.0.2.2"]);
+    }
+}
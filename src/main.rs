@@ -1,371 +1,601 @@
 extern crate tokio;
 
 use std::collections::HashMap;
+use std::env;
 use std::env::current_dir;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::path::{PathBuf};
-use std::str::FromStr;
-use std::time::Duration;
-use serde_json::Value;
-use surge_ping::{PingIdentifier, PingSequence};
-use tokio::time::{Instant, timeout};
-use crate::iplookup::IpInfoClientWrapper;
-
-mod iplookup;
-
-type CountryCode = String;// like DE, PL
-type City = String;
-type IP = String;// either v4 or v6
-type Rtt = f64;// Round Trip Time / latency / ping time
-
-const IPINFO_SECRET: &str = "...";
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{error, info, warn};
+use tokio::signal;
+use tokio::sync::watch;
+use tokio::time;
+use tokio::time::Instant;
+use geoping::{CountryCode, GeopingError, Servers};
+use geoping::{check_sample_sizes, collect_servers, country_stats, format_lint_report, format_ranking, format_server_table, enrich_locations, filter_cities, filter_countries, generate_csv, generate_detailed_csv, generate_geojson, generate_html, generate_json, generate_metadata, generate_ndjson, generate_prometheus, generate_unreachable_csv, keep_top, lint_report_json, lint_servers, ndjson_lines, load_combined, load_partial, merge_results, open_probe, ping_servers, sample_servers, write_output, write_sqlite};
+use geoping::{Compression, CountryCorrection, EntryFields, FallbackProvider, GeoProvider, IpApiProvider, IpVersion, MaxMindProvider, Measurement, OutputFormat, PartialWriter, PingOptions, Probe, RunMetadata};
+use geoping::progress::Verbosity;
+use geoping::iplookup::{IpInfoClientWrapper, RetryPolicy};
+use crate::cli::{Args, Command, GeoFallback, GeoProviderKind, DEFAULT_MERGED_OUTPUT, DEFAULT_OUTPUT_STEM};
+
+mod cli;
+mod config;
+
+const IPINFO_TOKEN_ENV: &str = "GEOPING_IPINFO_TOKEN";
 const IPINFO_TIMEOUT: u64 = 15000;// ms
-const MAX_RTT: f64 = 500.0f64;// ms
-const PING_COUNT: u16 = 10;// per IP
+const FREE_GEO_TIMEOUT: u64 = 15000;// ms
+const RANKING_TOP: usize = 10;// countries printed once the output is written
 
-fn obtain_country_code_from_filepath(path: &PathBuf) -> CountryCode
+/// The environment variable wins over the config file
+fn obtain_ipinfo_token(config_token: Option<&str>) -> Option<String>
 {
-    path.file_name().unwrap().to_string_lossy()
-        .split(".")
-        .next().unwrap()
-        .to_uppercase()
+    match env::var(IPINFO_TOKEN_ENV)
+    {
+        Ok(token) if !token.trim().is_empty() => Some(token),
+        _ if config_token.is_some_and(|token| !token.trim().is_empty()) => config_token.map(str::to_string),
+        _ => None
+    }
 }
 
-fn gather_files_with_ext(dir: &PathBuf, extension: &str) -> Vec<PathBuf>
+/// None if the selected provider can't be used, in which case locations are left as listed
+fn open_geo_provider(args: &Args, config_token: Option<&str>) -> Option<Box<dyn GeoProvider>>
 {
-    let mut paths = vec![];
-
-    for dir in fs::read_dir(dir)
+    match args.geo_provider
     {
-        for file in dir.flatten()
-        {
-            if let Ok(kind) = file.file_type()
+        GeoProviderKind::Ipinfo => {
+            let Some(token) = obtain_ipinfo_token(config_token) else {
+                warn!("No IpInfo token found, set {} to correct locations - skipping", IPINFO_TOKEN_ENV);
+                return None;
+            };
+
+            let ipinfo_client = match IpInfoClientWrapper::new(
+                token.as_str(),
+                Duration::from_millis(IPINFO_TIMEOUT),
+                &args.ipinfo_cache,
+                args.ipinfo_concurrency as usize,
+                RetryPolicy {
+                    retries: args.ipinfo_retries,
+                    base_delay: Duration::from_millis(args.ipinfo_retry_delay_ms),
+                    rate_limit_pause: Duration::from_secs(args.ipinfo_rate_limit_pause_secs),
+                    max_rate_limit_wait: Duration::from_secs(args.ipinfo_max_wait_secs)
+                },
+                args.max_requests
+            )
             {
-                // regular file
-                if kind.is_file()
-                {
-                    let path = file.path();
+                Ok(client) => client,
+                Err(err) => {
+                    error!("Could not set up IpInfo lookups, {} - skipping", err);
+                    return None;
+                }
+            };
 
-                    if let Some(ext) = path.extension()
-                    {
-                        if ext == extension
-                        {
-                            paths.push(path);
-                        }
-                    }
+            Some(Box::new(ipinfo_client))
+        }
+        GeoProviderKind::Maxmind => {
+            // required by clap along with --geo-provider maxmind
+            let path = args.mmdb.as_ref().unwrap();
+
+            match MaxMindProvider::open(path)
+            {
+                Ok(provider) => Some(Box::new(provider)),
+                Err(err) => {
+                    error!("Could not open MaxMind database {}: {} - skipping", path.to_string_lossy(), err);
+                    None
                 }
             }
         }
     }
-
-    paths
 }
 
-fn collect_servers() -> HashMap<CountryCode, Vec<(City, IP)>>
+/// `provider` followed by the --geo-fallback one, or only the latter if `provider` can't be used
+fn with_geo_fallback(args: &Args, provider: Option<Box<dyn GeoProvider>>) -> Option<Box<dyn GeoProvider>>
 {
-    let mut countries = HashMap::new();
+    let Some(GeoFallback::Free) = args.geo_fallback else { return provider; };
 
-    let paths = gather_files_with_ext(&current_dir().unwrap(), "json");
-
-    println!("JSON files should be placed at {}", current_dir().unwrap().to_string_lossy());
-    println!("Loaded {} countries", paths.len());
+    let fallback = match IpApiProvider::new(Duration::from_millis(FREE_GEO_TIMEOUT))
+    {
+        Ok(fallback) => fallback,
+        Err(err) => {
+            error!("Could not set up ip-api.com lookups, {} - going without them", err);
+            return provider;
+        }
+    };
 
-    for path in paths
+    match provider
     {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(&path)
-            .unwrap();
+        Some(provider) => Some(Box::new(FallbackProvider::new(provider, Box::new(fallback)))),
+        None => {
+            warn!("Looking locations up with ip-api.com instead");
+            Some(Box::new(fallback))
+        }
+    }
+}
 
-        let cc = obtain_country_code_from_filepath(&path);
+/// Logs what went wrong and quits with an exit code telling what kind of problem it was
+fn fail<T>(err: GeopingError) -> T
+{
+    error!("{}", err);
+    process::exit(err.exit_code());
+}
 
-        let mut content = String::new();
-        file.read_to_string(&mut content)
-            .unwrap();
+/// Lists how many servers each country has with a few of their IPs, for checking input files
+fn print_servers(servers: &Servers, servers_count: u64)
+{
+    const PREVIEW: usize = 3;// IPs from both ends of a list
 
-        let json: Value = serde_json::from_str(content.as_str()).unwrap();
-        let entries = json
-                                  .as_array()
-                                  .unwrap();
+    let mut countries: Vec<_> = servers.iter().collect();
+    countries.sort_by_key(|(cc, _)| *cc);
 
-        let mut cities = vec![];
+    for (cc, cities) in countries
+    {
+        let ips: Vec<&str> = cities.iter().map(|(_, ip)| ip.as_str()).collect();
 
-        for entry in entries
+        if ips.len() > 2 * PREVIEW
         {
-            let ip = entry["ip"].as_str().unwrap().to_string();
-            let city = entry["city"].as_str().unwrap().to_string();
-
-            cities.push((city, ip));
+            println!("{}: {} servers - {} ... {}", cc, ips.len(), ips[..PREVIEW].join(", "), ips[ips.len() - PREVIEW..].join(", "));
+        }
+        else
+        {
+            println!("{}: {} servers - {}", cc, ips.len(), ips.join(", "));
         }
-
-        countries.insert(cc, cities);
     }
 
-    countries
+    println!("Total: {} servers", servers_count);
 }
 
-async fn ping_servers(servers: HashMap<CountryCode, Vec<(City, IP)>>, servers_count: u64) -> HashMap<CountryCode, Vec<(City, IP, Rtt)>>
+#[tokio::main]
+async fn main()
 {
-    use surge_ping::{Config, Client};
+    let (args, config) = config::parse_args();
 
-    let mut rtts = HashMap::new();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let cfg = Config::default();
-    let client = Client::new(&cfg).unwrap();
+    if let Some(Command::Merge { files }) = &args.command
+    {
+        let output_path = args.output.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_MERGED_OUTPUT));
 
-    let timeo = Duration::from_millis(MAX_RTT as u64);
+        let csv = merge_results(files, args.locale).unwrap_or_else(fail);
+        write_output(&output_path, csv.as_str(), args.compress).unwrap_or_else(fail);
 
-    let mut count_now = 0;
+        info!("Merged {} files into {}", files.len(), args.compress.path(&output_path).to_string_lossy());
 
-    for (cc, cities) in servers
-    {
-        // not using .iter().enumerate()
-        // so no extra .clone() on e.g. city.clone() while pushing result
+        return;
+    }
 
-        let mut id = 0;
+    let timer = Instant::now();
 
-        for (city, ip) in &cities
-        {
-            let mut parsed_ip: Option<IpAddr> = None;
+    let input_dirs = if args.input_dirs.is_empty() { vec![current_dir().unwrap()] } else { args.input_dirs.clone() };
+    let output_path = args.output.clone().unwrap_or_else(|| {
+        PathBuf::from(format!("{}.{}", DEFAULT_OUTPUT_STEM, args.format.extension()))
+    });
 
-            if let Ok(addr) = Ipv4Addr::from_str(ip.as_str()) { parsed_ip = Some(IpAddr::V4(addr)); }
-            if let Ok(addr) = Ipv6Addr::from_str(ip.as_str()) { parsed_ip = Some(IpAddr::V6(addr)); }
+    let mut outputs: Vec<PathBuf> = [Some(&output_path), args.detailed.as_ref(), args.unreachable_out.as_ref()].into_iter().flatten().map(|path| args.compress.path(path)).collect();
 
-            if parsed_ip.is_none() { continue; }
+    // written by geoping too, so never read as server files
+    outputs.extend([args.ipinfo_cache.clone(), args.partial.clone()]);
 
-            let mut pinger = client.pinger(parsed_ip.unwrap(), PingIdentifier(id as u16)).await;
+    // JSON like the server files
+    if args.meta { outputs.push(meta_path(&args.compress.path(&output_path))); }
 
-            let mut min_rtt = MAX_RTT;
-            'ping_loop: for i in 0..PING_COUNT
-            {
-                match timeout(timeo, pinger.ping(PingSequence(i), &[])).await
-                {
-                    Ok(ping_result) => {
-                        if let Ok((_, duration)) = ping_result {
+    let fields = EntryFields { ip: args.ip_field.clone(), city: args.city_field.clone() };
+    let (mut servers, diagnostics, server_concurrency) = match &args.combined
+    {
+        Some(path) => load_combined(path, &fields).unwrap_or_else(fail),
+        None => collect_servers(&input_dirs, &outputs, &fields, args.glob.as_ref()),
+    };
 
-                            let rtt = (duration.as_nanos() as f64) / 1_000_000.0f64;
+    if let Some(Command::Lint { json }) = &args.command
+    {
+        let issues = lint_servers(&servers);
 
-                            if rtt < min_rtt { min_rtt = rtt; }
-                        }
-                    }
-                    Err(_) => { /* timed out */ break 'ping_loop; }
-                }
-            }
+        if *json { println!("{}", lint_report_json(&diagnostics, &issues)); }
+        else { print!("{}", format_lint_report(&diagnostics, &issues)); }
 
-            if min_rtt < MAX_RTT
-            {
-                if id % 10 == 0
-                {
-                    println!("{} {:.0}%, {:.2} ms", cc, (((id as f64) / (cities.len() as f64)) * 100.0f64).round(), min_rtt);
-                }
+        process::exit(if diagnostics.is_empty() && issues.is_empty() { 0 } else { 1 });
+    }
 
-                rtts.entry(cc.clone())
-                    .or_insert(vec![])
-                    .push((city.clone(), ip.clone(), min_rtt));
-            }
+    if args.strict && !diagnostics.is_empty()
+    {
+        error!("{} problems in the server files, fix them or run without --strict", diagnostics.len());
+        process::exit(1);
+    }
 
-            id += 1;
-        }
+    if !args.countries.is_empty()
+    {
+        filter_countries(&mut servers, &args.countries);
+    }
 
-        count_now += cities.len();
-        println!("Total {:.0}%", 100.0f64 * (count_now as f64) / (servers_count as f64));
+    if let Some(pattern) = &args.city_filter
+    {
+        filter_cities(&mut servers, pattern);
     }
 
-    rtts
-}
+    if let Some(max) = args.sample
+    {
+        sample_servers(&mut servers, max as usize, args.sample_seed);
+    }
 
-async fn fill_empty_locations(rtts: &mut HashMap<CountryCode, Vec<(City, IP, Rtt)>>, servers_count: u64, ipinfo_client: &mut IpInfoClientWrapper)
-{
-    println!("Filling empty locations...");
+    let mut resumed = HashMap::new();
 
-    let mut count_now = 0u64;
-    let mut pct_prev = 0.0f64;
+    if args.resume
+    {
+        resumed = load_partial(&args.partial);
+        servers.retain(|cc, _| !resumed.contains_key(cc));
+
+        info!("Resuming, {} countries already pinged", resumed.len());
+    }
 
-    for (_, cities) in rtts.iter_mut()
+    if args.dry_run
     {
-        for (city, ip, _) in cities.iter_mut()
-        {
-            if city.trim().is_empty()
-            {
-                match ipinfo_client.query(ip.as_str()).await
-                {
-                    Ok(res) => {
-                        *city = res.city;
-                    }
-                    Err(err) => { println!("Could not get city for IP {}: {}", ip, err); }
-                }
-            }
+        let count_total = servers.values().map(|cities| cities.len() as u64).sum();
 
-            count_now += 1;
-            let pct = ((count_now as f64) / (servers_count as f64)) * 100.0f64;
-            if pct >= (pct_prev + 5.0f64)
-            {
-                println!("{:.01}%", pct);
+        print_servers(&servers, count_total);
+        return;
+    }
 
-                pct_prev = pct;
-            }
+    // packets can only be sent from an address to servers of the same family
+    let ip_version = match (args.bind, args.ip_version)
+    {
+        (Some(bind), IpVersion::Both) => {
+            info!("Only pinging IPv{} addresses, like {}", if bind.is_ipv4() { 4 } else { 6 }, bind);
+            if bind.is_ipv4() { IpVersion::V4 } else { IpVersion::V6 }
+        }
+        (Some(bind), ip_version) if !ip_version.allows(&bind) => {
+            error!("--bind {} can't ping the addresses --ip-version asks for", bind);
+            process::exit(1);
         }
+        (_, ip_version) => ip_version,
+    };
+
+    let ping_opts = PingOptions {
+        count: args.count,
+        warmup: args.warmup,
+        probe_interval: Duration::from_millis(args.probe_interval_ms),
+        pps: args.pps,
+        payload_bytes: args.payload_bytes as usize,
+        timeout: Duration::from_millis(args.timeout_ms),
+        server_budget: args.server_budget_ms.map(Duration::from_millis),
+        max_acceptable_rtt: args.max_acceptable_rtt_ms.map(|ms| ms as f64),
+        keep_slow: args.keep_slow,
+        concurrency: args.concurrency as usize,
+        server_concurrency,
+        country_concurrency: args.country_concurrency as usize,
+        dedupe: args.dedupe,
+        shuffle: args.shuffle.map(|seed| seed.unwrap_or_else(rand::random)),
+        ip_version,
+        exclude: args.exclude_cidr.clone(),
+        fallback: args.fallback,
+        tcp_port: args.tcp_port,
+        bind: args.bind,
+        verbosity: args.verbosity()
+    };
+
+    let (stop, mut stopped) = watch::channel(false);
+
+    tokio::spawn({
+        let stop = stop.clone();
+
+        async move {
+            if signal::ctrl_c().await.is_err() { return; }
+
+            warn!("Interrupted, finishing up - press Ctrl-C again to quit right away");
+            stop.send_replace(true);
+
+            if signal::ctrl_c().await.is_err() { return; }
+
+            process::exit(130);
+        }
+    });
+
+    let deadline = args.deadline_seconds.map(|secs| timer + Duration::from_secs(secs));
+
+    if let Some(deadline) = deadline
+    {
+        let stop = stop.clone();
+
+        // servers in flight are cut short, so the output gets written right away
+        tokio::spawn(async move {
+            time::sleep_until(deadline).await;
+
+            warn!("Deadline reached, writing partial results");
+            stop.send_replace(true);
+        });
     }
 
-    println!("100%");
-}
+    // opened once so every --interval cycle shares the IpInfo cache
+    let provider = match args.country_correction()
+    {
+        CountryCorrection::Off => None,
+        _ => with_geo_fallback(&args, open_geo_provider(&args, config.ipinfo_token.as_deref())),
+    };
 
-async fn fix_countries(rtts: &mut HashMap<CountryCode, Vec<(City, IP, Rtt)>>, servers_count: u64, ipinfo_client: &mut IpInfoClientWrapper)
-{
-    println!("Fixing locations...");
+    if args.format == OutputFormat::Geojson && provider.is_none()
+    {
+        error!("--format geojson needs the coordinates of a geo provider, set one up and keep location correction on");
+        process::exit(1);
+    }
 
-    let mut count_now = 0u64;
-    let mut pct_prev = 0.0f64;
+    // a single socket for every scan of --interval
+    let probe = match open_probe(&ping_opts)
+    {
+        Ok(probe) => probe,
+        Err(err @ GeopingError::PingClient(_)) => {
+            error!("{} - run as root, allow raw sockets with `sudo setcap cap_net_raw+ep {}` \
+                or time TCP connects instead with --fallback tcp", err, env::current_exe().map(|exe| exe.to_string_lossy().to_string()).unwrap_or("geoping".to_string()));
+            process::exit(err.exit_code());
+        }
+        Err(err) => fail(err),
+    };
 
-    let mut modifiers = vec![];
+    let scanner = Scanner { args: &args, ping_opts, probe, provider, output_path, stop: stopped.clone(), deadline };
 
-    for (cc, cities) in rtts.iter()
+    loop
     {
-        for (i, (city, ip, rtt)) in cities.iter().enumerate()
-        {
-            match ipinfo_client.query(ip.as_str()).await
-            {
-                Ok(details) => {
-                    if details.country != *cc
-                    {
-                        modifiers.push((cc.clone(), i, details.country, city.clone(), ip.clone(), *rtt));
-                    }
-                }
-                Err(err) => { println!("Could not resolve country for city {} for IP {}: {}", city, ip, err); }
-            }
+        let stamp = args.interval.map(|_| SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default());
 
-            count_now += 1;
-            let pct = ((count_now as f64) / (servers_count as f64)) * 100.0f64;
-            if pct >= (pct_prev + 5.0f64)
-            {
-                println!("{:.01}%", pct);
+        let interrupted = scanner.scan(servers.clone(), mem::take(&mut resumed), stamp).await;
 
-                pct_prev = pct;
-            }
+        let Some(interval) = args.interval else { break; };
+        if interrupted { break; }
+
+        info!("Next scan in {}s, press Ctrl-C to quit", interval.as_secs());
+
+        tokio::select! {
+            _ = time::sleep(interval) => {}
+            _ = stopped.wait_for(|stop| *stop) => { break; }
         }
     }
 
-    for (cc_estimated, i, _, _, _, _) in modifiers.iter().rev()
+    if let Some(stats) = scanner.provider.as_ref().and_then(|provider| provider.cache_stats())
     {
-        rtts.get_mut(cc_estimated).unwrap().remove(*i);
+        info!("IpInfo requests this run: {}", stats.requests);
     }
 
-    for (_, _, cc_real, city, ip, rtt) in modifiers
+    info!("Done!, it took {}s", timer.elapsed().as_secs());
+}
+
+/// Sidecar of an output for --meta
+fn meta_path(output: &Path) -> PathBuf
+{
+    let mut name = output.as_os_str().to_os_string();
+    name.push(".meta.json");
+    PathBuf::from(name)
+}
+
+/// Inserts `-<stamp>` before the extension, --interval cycles each write their own files
+fn stamped(path: &Path, stamp: Option<u64>) -> PathBuf
+{
+    let Some(stamp) = stamp else { return path.to_path_buf(); };
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    match path.extension()
     {
-        if let Some(entry) = rtts.get_mut(&cc_real) {
-            entry.push((city, ip, rtt));
-        }
+        Some(ext) => path.with_file_name(format!("{}-{}.{}", stem, stamp, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}-{}", stem, stamp)),
     }
+}
 
-    println!("100%");
+/// What stays the same between the scans of --interval
+struct Scanner<'a>
+{
+    args: &'a Args,
+    ping_opts: PingOptions,
+    probe: Box<dyn Probe>,
+    provider: Option<Box<dyn GeoProvider>>,
+    output_path: PathBuf,
+    stop: watch::Receiver<bool>,
+    deadline: Option<Instant>,
 }
 
-fn generate_csv(rtts: &mut HashMap<CountryCode, Vec<(City, IP, Rtt)>>) -> String
+impl Scanner<'_>
 {
-    let mut csv = String::new();
+    /// Pings, corrects and writes the output, true if the scan was interrupted.
+    /// Countries in `resumed` are only written out, `stamp` goes into the output file names.
+    async fn scan(&self, servers: Servers, resumed: HashMap<CountryCode, Vec<Measurement>>, stamp: Option<u64>) -> bool
+    {
+        let args = self.args;
+        let timer = Instant::now();
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default();
 
-    csv += "Country\tMin RTT\tMedian RTT\tAverage RTT\tMax RTT\n";
+        // 1. Ping
 
-    let mut intermediate = vec![];
+        info!("[Step 1] Pinging servers...");
 
-    for (cc, entries) in rtts
-    {
-        let mut min = MAX_RTT;
-        let mut max = 0.0f64;
-        let mut sum = 0.0f64;
+        let count_total = servers.values().map(|cities| cities.len() as u64).sum();
 
-        for (_, _, rtt) in entries.iter()
+        let mut partial = match PartialWriter::open(&args.partial, args.resume)
         {
-            if *rtt < min { min = *rtt; }
-            if *rtt > max { max = *rtt; }
-
-            sum += *rtt;
-        }
+            Ok(writer) => Some(writer),
+            Err(err) => {
+                warn!("Could not open partial results file {}: {}", args.partial.to_string_lossy(), err);
+                None
+            }
+        };
 
-        let average = sum / (entries.len() as f64);
+        let mut save_country = |cc: &CountryCode, measured: &[Measurement]| {
+            // as pinged, before their locations are corrected
+            if args.format == OutputFormat::Ndjson
+            {
+                let lines = ndjson_lines(cc, measured, args.loss_penalty, args.server_metric, args.detailed.is_some());
 
-        let median = {
-            let len = entries.len();
+                // a reader which went away, e.g. `| head`, shouldn't stop the scan
+                let mut stdout = io::stdout().lock();
+                let _ = stdout.write_all(lines.as_bytes()).and_then(|_| stdout.flush());
+            }
 
-            entries.sort_by(|(_, _, rtt1), (_, _, rtt2)| rtt1.partial_cmp(rtt2).unwrap());
+            let Some(writer) = partial.as_mut() else { return; };
 
-            if len % 2 == 0
+            if let Err(err) = writer.append(cc, measured)
             {
-                (
-                    entries[len / 2 - 1].2 +
-                    entries[len / 2 - 1].2
-                )
-                / 2.0f64
+                warn!("Could not write {} to partial results file: {}", cc, err);
             }
-            else { entries[len / 2].2 }
         };
 
-        intermediate.push((cc.clone(), min, median, average, max));
-    }
+        let (mut rtts, summary) = ping_servers(servers, count_total, &self.ping_opts, self.probe.as_ref(), &mut save_country, &self.stop).await;
 
-    intermediate.sort_by(|(_, min1, _, _, _), (_, min2, _, _, _)| min1.partial_cmp(min2).unwrap());
+        let interrupted = *self.stop.borrow();
+        let pinged = Instant::now();
 
-    for (cc, min, median, average, max) in intermediate
-    {
-        csv += cc.as_str(); csv.push('\t');
-        csv += format!("{:.03}", min).replace('.', ",").as_str(); csv.push('\t');
-        csv += format!("{:.03}", median).replace('.', ",").as_str(); csv.push('\t');
-        csv += format!("{:.03}", average).replace('.', ",").as_str(); csv.push('\t');
-        csv += format!("{:.03}", max).replace('.', ",").as_str(); csv.push('\n');
-    }
+        rtts.extend(resumed.into_iter().filter(|(_, measured)| !measured.is_empty()));
 
-    csv
-}
+        // before countries are moved, dropped or cut by --top
+        let nothing_replied = rtts.is_empty();
 
-#[tokio::main]
-async fn main()
-{
-    let mut timer = Instant::now();
+        if let Some(cc) = &args.debug_country
+        {
+            let cc = cc.trim().to_uppercase();
+            print!("{}", format_server_table(&cc, rtts.get(&cc).map(Vec::as_slice).unwrap_or_default(), &summary));
+        }
 
-    let mut ipinfo_client = IpInfoClientWrapper::new(
-        IPINFO_SECRET,
-        Duration::from_millis(IPINFO_TIMEOUT)
-    ).unwrap();
+        // 2. Correct
 
-    // 1. Ping
+        if interrupted
+        {
+            info!("[Step 2] Skipping location correction of an interrupted scan");
+        }
+        else if args.country_correction() == CountryCorrection::Off
+        {
+            info!("[Step 2] Location correction is off");
+        }
+        else if let Some(provider) = &self.provider
+        {
+            match args.country_correction()
+            {
+                CountryCorrection::Fill => info!("[Step 2] Filling in missing cities..."),
+                _ => info!("[Step 2] Correcting locations..."),
+            }
 
-    println!("[Step 1] Pinging servers...");
+            let stats_before = provider.cache_stats();
 
-    let servers = collect_servers();
+            let correction = enrich_locations(&mut rtts, provider.as_ref(), args.ipinfo_concurrency as usize, args.verbosity(), args.country_correction());
 
-    let mut count_total = 0u64;
-    for (_, cities) in servers.iter()
-    {
-        count_total += cities.len() as u64;
-    }
+            // servers are only moved once every lookup is done, so cutting the lookups short leaves them as listed
+            match self.deadline
+            {
+                Some(deadline) => {
+                    if time::timeout_at(deadline, correction).await.is_err()
+                    {
+                        warn!("Location correction didn't finish before the deadline, servers are left as listed");
+                    }
+                }
+                None => { correction.await; }
+            }
+
+            if let Err(err) = provider.flush()
+            {
+                warn!("Could not write IpInfo cache {}: {}", args.ipinfo_cache.to_string_lossy(), err);
+            }
+
+            if let (Some(before), Some(after)) = (stats_before, provider.cache_stats())
+            {
+                let stats = after.since(before);
+                info!("{} IPs answered from the cache, {} looked up in {} requests", stats.hits, stats.misses, stats.requests);
+            }
+        }
+
+        check_sample_sizes(&mut rtts, args.min_samples as usize, args.drop_small);
+        let corrected = Instant::now();
+
+        // 3. Output
+
+        info!("[Step 3] Generating output...");
+
+        // the history keeps every country
+        if let Some(path) = &args.sqlite
+        {
+            if let Err(err) = write_sqlite(path, &rtts)
+            {
+                error!("{}", err);
+            }
+        }
+
+        if let Some(top) = args.top
+        {
+            keep_top(&mut rtts, top as usize, args.loss_penalty, args.server_metric);
+        }
+
+        let output_path = stamped(&self.output_path, stamp);
 
-    let mut rtts = ping_servers(servers, count_total).await;
+        let output = match args.format
+        {
+            OutputFormat::Csv => generate_csv(&rtts, args.locale, args.precision as usize, args.loss_penalty, args.server_metric, &summary, args.with_country_name),
+            OutputFormat::Json => generate_json(&rtts, args.loss_penalty, args.server_metric),
+            OutputFormat::Html => generate_html(&rtts, args.loss_penalty, args.server_metric),
+            OutputFormat::Ndjson => generate_ndjson(&rtts, args.loss_penalty, args.server_metric, args.detailed.is_some()),
+            OutputFormat::Geojson => generate_geojson(&rtts),
+            OutputFormat::Prometheus => generate_prometheus(&rtts, args.loss_penalty, args.server_metric, &summary),
+        };
+        write_output(&output_path, output.as_str(), args.compress).unwrap_or_else(fail);
 
-    // 2. Correct
+        // stdout carries the ndjson stream
+        if args.verbosity() != Verbosity::Quiet && args.format != OutputFormat::Ndjson
+        {
+            let stats = country_stats(&rtts, args.loss_penalty, args.server_metric);
 
-    println!("[Step 2] Correcting locations...");
+            if !stats.is_empty()
+            {
+                println!("Top {} of {} countries by median RTT:", RANKING_TOP.min(stats.len()), stats.len());
+                print!("{}", format_ranking(&stats, RANKING_TOP));
+            }
+        }
 
-    //fill_empty_locations(&mut rtts, count_total, &mut ipinfo_client).await;
-    fix_countries(&mut rtts, count_total, &mut ipinfo_client).await;
+        if let Some(path) = &args.detailed
+        {
+            let csv = generate_detailed_csv(&rtts, args.locale, args.precision as usize, args.enrich_asn);
+            write_output(&stamped(path, stamp), csv.as_str(), args.compress).unwrap_or_else(fail);
+        }
 
-    // 3. Output
+        if let Some(path) = &args.unreachable_out
+        {
+            let csv = generate_unreachable_csv(&summary, args.locale);
+            write_output(&stamped(path, stamp), csv.as_str(), args.compress).unwrap_or_else(fail);
+        }
 
-    println!("[Step 3] Generating CSV...");
+        if args.meta
+        {
+            let meta = RunMetadata {
+                started,
+                seconds: timer.elapsed().as_secs(),
+                hostname: gethostname::gethostname().to_string_lossy().to_string(),
+                opts: &self.ping_opts,
+                probe: rtts.values().flatten().next().map(|server| server.probe),
+                servers: count_total,
+                summary: &summary,
+                interrupted,
+            };
+
+            write_output(&meta_path(&args.compress.path(&output_path)), generate_metadata(&meta).as_str(), Compression::None).unwrap_or_else(fail);
+        }
 
-    let csv = generate_csv(&mut rtts);
+        if interrupted
+        {
+            warn!("Partial results were written to {}, run again with --resume to ping the rest", output_path.to_string_lossy());
+        }
+        else if partial.take().is_some()
+        {
+            if let Err(err) = fs::remove_file(&args.partial)
+            {
+                warn!("Could not remove partial results file {}: {}", args.partial.to_string_lossy(), err);
+            }
+        }
 
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open("rtt_result.csv")
-        .unwrap();
+        info!("Reachable: {}, Unreachable: {}, Slow: {}, Invalid: {}", summary.reachable, summary.unreachable.len(), summary.slow.len(), summary.invalid.len());
+        // tells whether more concurrency or less correction would speed runs up
+        info!("Pinging took {:.1}s, correction {:.1}s, output {:.1}s",
+            (pinged - timer).as_secs_f64(), (corrected - pinged).as_secs_f64(), corrected.elapsed().as_secs_f64());
+        info!("Scan took {}s", timer.elapsed().as_secs());
 
-    file.write_all(csv.as_bytes()).unwrap();
-    file.flush().unwrap();
+        if args.fail_on_empty && nothing_replied && !interrupted
+        {
+            error!("None of the servers replied, check the network and the server files");
+            process::exit(1);
+        }
 
-    println!("Done!, it took {}s", timer.elapsed().as_secs());
+        interrupted
+    }
 }
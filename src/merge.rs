@@ -0,0 +1,139 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::CountryCode;
+use crate::error::GeopingError;
+use crate::output::OutputStyle;
+
+/// Row of a country in one of the merged files
+struct Row
+{
+    country: CountryCode,
+    min: f64,
+    score: f64,
+    fields: Vec<String>,// as written, quoted where needed
+    vantage: String,
+}
+
+/// Splits a row written by [`OutputStyle::row`], only `std` quotes fields
+fn split_fields(line: &str, style: OutputStyle) -> Vec<String>
+{
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next()
+    {
+        match c
+        {
+            '"' if style == OutputStyle::Csv && quoted && chars.peek() == Some(&'"') => { field.push('"'); chars.next(); }
+            '"' if style == OutputStyle::Csv => { quoted = !quoted; }
+            c if c == style.separator() && !quoted => { fields.push(std::mem::take(&mut field)); }
+            c => { field.push(c); }
+        }
+    }
+
+    fields.push(field);
+    fields
+}
+
+fn parse_number(field: &str, style: OutputStyle) -> Option<f64>
+{
+    match style
+    {
+        OutputStyle::Csv => field.parse().ok(),
+        OutputStyle::Tsv => field.replace(',', ".").parse().ok(),
+    }
+}
+
+/// Header and rows of a CSV written by `generate_csv`
+fn parse_results(path: &Path, style: OutputStyle) -> Result<(Vec<String>, Vec<Row>), GeopingError>
+{
+    let content = fs::read_to_string(path).map_err(|source| GeopingError::Read { path: path.to_path_buf(), source })?;
+    let mut lines = content.lines();
+
+    let header = split_fields(lines.next().unwrap_or_default(), style);
+
+    let column = |name: &str| header.iter().position(|field| field == name)
+        .ok_or_else(|| GeopingError::NotResults { path: path.to_path_buf(), problem: format!("no \"{}\" column", name) });
+
+    let country = column("Country")?;
+    let min = column("Min RTT")?;
+    let score = column("Score")?;
+
+    let vantage = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let mut rows = vec![];
+
+    for (i, line) in lines.enumerate()
+    {
+        if line.trim().is_empty() { continue; }
+
+        let fields = split_fields(line, style);
+        let error = || GeopingError::ResultsRow { path: path.to_path_buf(), line: i + 2 };
+
+        if fields.len() != header.len() { return Err(error()); }
+
+        rows.push(Row {
+            country: fields[country].clone(),
+            min: parse_number(&fields[min], style).ok_or_else(error)?,
+            score: parse_number(&fields[score], style).ok_or_else(error)?,
+            fields: fields.iter().map(|field| style.text(field)).collect(),
+            vantage: vantage.clone(),
+        });
+    }
+
+    Ok((header, rows))
+}
+
+/// Combines CSV outputs of runs from several vantage points, all written with the same `style`
+/// and version. Every country keeps the whole row of the file with its lowest min RTT, so its
+/// statistics stay those of a single run, and a Vantage column names that file (its stem).
+/// Countries are ranked by score like in a single run.
+pub fn merge_results(paths: &[PathBuf], style: OutputStyle) -> Result<String, GeopingError>
+{
+    let mut header: Option<Vec<String>> = None;
+    let mut best: Vec<Row> = vec![];
+
+    for path in paths
+    {
+        let (file_header, rows) = parse_results(path, style)?;
+
+        match &header
+        {
+            Some(header) if *header != file_header => {
+                return Err(GeopingError::NotResults { path: path.to_path_buf(), problem: "its columns differ from the other files".to_string() });
+            }
+            Some(_) => {}
+            None => { header = Some(file_header); }
+        }
+
+        for row in rows
+        {
+            match best.iter_mut().find(|kept| kept.country == row.country)
+            {
+                Some(kept) if row.min < kept.min => { *kept = row; }
+                Some(_) => {}
+                None => { best.push(row); }
+            }
+        }
+    }
+
+    best.sort_by(|row1, row2| {
+        row1.score.total_cmp(&row2.score)
+            .then(row1.min.total_cmp(&row2.min))
+    });
+
+    let mut csv = String::new();
+
+    let mut header: Vec<String> = header.unwrap_or_default().iter().map(|field| style.text(field)).collect();
+    header.push(style.text("Vantage"));
+    csv += style.row(&header).as_str();
+
+    for mut row in best
+    {
+        row.fields.push(style.text(row.vantage.as_str()));
+        csv += style.row(&row.fields).as_str();
+    }
+
+    Ok(csv)
+}
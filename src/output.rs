@@ -0,0 +1,1077 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use log::warn;
+use serde_json::{json, Value};
+use crate::{City, CountryCode, IP, Rtt};
+use crate::error::GeopingError;
+use crate::ping::{Measurement, PingOptions, PingSummary, ProbeKind};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputStyle
+{
+    /// Comma separated fields, dot as decimal separator
+    #[value(name = "std")]
+    Csv,
+    /// Tab separated fields, comma as decimal separator - what European spreadsheets expect
+    #[value(name = "eu")]
+    Tsv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat
+{
+    Csv,
+    /// Statistics of every country along with its servers
+    Json,
+    /// Self-contained page with a sortable table
+    Html,
+    /// One JSON object per line, also streamed to stdout as every country finishes
+    Ndjson,
+    /// FeatureCollection of every server the geo provider has coordinates of, for Leaflet or QGIS
+    Geojson,
+    /// Exposition format for the node_exporter textfile collector
+    Prometheus,
+}
+
+/// How output files are compressed, their extension is appended to the path
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression
+{
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression
+{
+    pub fn extension(&self) -> Option<&'static str>
+    {
+        match self
+        {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+
+    /// `path` with the extension of the compression, unless it has it already
+    pub fn path(&self, path: &Path) -> PathBuf
+    {
+        match self.extension()
+        {
+            Some(ext) if path.extension().is_none_or(|current| current != ext) => {
+                let mut name = path.as_os_str().to_os_string();
+                name.push(".");
+                name.push(ext);
+                PathBuf::from(name)
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn encode(&self, content: &[u8]) -> io::Result<Vec<u8>>
+    {
+        match self
+        {
+            Compression::None => Ok(content.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(content, 0),
+        }
+    }
+}
+
+/// Which RTT of every server the country statistics are built from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerMetric
+{
+    /// Lowest of the replies, the best the path allows
+    Min,
+    /// Mean of the replies, also counting the queueing and retransmission delays a server usually sees
+    Mean,
+}
+
+impl ServerMetric
+{
+    pub fn rtt(&self, server: &Measurement) -> Rtt
+    {
+        match self
+        {
+            ServerMetric::Min => server.rtt,
+            ServerMetric::Mean => server.mean(),
+        }
+    }
+}
+
+impl OutputFormat
+{
+    pub fn extension(&self) -> &'static str
+    {
+        match self
+        {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::Html => "html",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Geojson => "geojson",
+            OutputFormat::Prometheus => "prom",
+        }
+    }
+}
+
+impl OutputStyle
+{
+    pub fn separator(&self) -> char
+    {
+        match self
+        {
+            OutputStyle::Csv => ',',
+            OutputStyle::Tsv => '\t',
+        }
+    }
+
+    /// `value` with `precision` decimals
+    pub fn number(&self, value: f64, precision: usize) -> String
+    {
+        match self
+        {
+            OutputStyle::Csv => format!("{:.*}", precision, value),
+            OutputStyle::Tsv => format!("{:.*}", precision, value).replace('.', ","),
+        }
+    }
+
+    pub fn text(&self, value: &str) -> String
+    {
+        match self
+        {
+            OutputStyle::Csv if value.contains([',', '"', '\n']) => format!("\"{}\"", value.replace('"', "\"\"")),
+            _ => value.to_string(),
+        }
+    }
+
+    /// Empty field when there's no value, e.g. TTL of IPv6 servers
+    pub fn ttl(&self, ttl: Option<u8>) -> String
+    {
+        ttl.map(|ttl| ttl.to_string()).unwrap_or_default()
+    }
+
+    pub fn row(&self, fields: &[String]) -> String
+    {
+        let mut row = fields.join(self.separator().to_string().as_str());
+        row.push('\n');
+        row
+    }
+}
+
+/// Nearest-rank percentile of servers sorted by `metric`, with too few servers
+/// the rank falls on the last one so it's just the max
+fn percentile(sorted: &[&Measurement], pct: f64, metric: ServerMetric) -> Rtt
+{
+    let rank = ((pct / 100.0f64) * (sorted.len() as f64)).ceil() as usize;
+
+    metric.rtt(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// Servers from the fastest to the slowest by their `metric` RTT, leaving `servers` as they are
+fn by_rtt(servers: &[Measurement], metric: ServerMetric) -> Vec<&Measurement>
+{
+    let mut sorted: Vec<&Measurement> = servers.iter().collect();
+    sorted.sort_by(|s1, s2| metric.rtt(s1).total_cmp(&metric.rtt(s2)));
+    sorted
+}
+
+pub struct CountryStats
+{
+    pub country: CountryCode,
+    pub min: Rtt,
+    pub median: Rtt,
+    pub p95: Rtt,
+    pub p99: Rtt,
+    pub average: Rtt,
+    pub max: Rtt,
+    pub loss: f64,// % of echo requests without reply
+    pub jitter: Rtt,// spread of the replies of a single server, averaged over the servers
+    pub ttl: Option<u8>,// of the fastest server
+    pub samples: usize,// servers the statistics are based on
+    pub score: f64,// median RTT * (1 + loss fraction * loss penalty), lower is better
+    pub probe: ProbeKind,// how the servers were measured, the same for a whole run
+}
+
+/// Statistics of a single country's servers, None if it has none left. Min, median, average and
+/// the rest are taken over the `metric` RTT of every server.
+///
+/// The score ranks countries by latency and loss at once: `median * (1 + loss / 100 * loss_penalty)`,
+/// so with a penalty of 1 a country losing 10% of its packets scores like a 10% slower one.
+pub fn aggregate(country: &CountryCode, entries: &[Measurement], loss_penalty: f64, metric: ServerMetric) -> Option<CountryStats>
+{
+    // every server of a country may have been moved to another one
+    if entries.is_empty() { return None; }
+
+    let mut min = metric.rtt(&entries[0]);
+    let mut max = min;
+    let mut sum = 0.0f64;
+    let mut sent = 0u64;
+    let mut lost = 0u64;
+    let mut jitter_sum = 0.0f64;
+
+    for server in entries.iter()
+    {
+        let rtt = metric.rtt(server);
+
+        if rtt < min { min = rtt; }
+        if rtt > max { max = rtt; }
+
+        sum += rtt;
+        sent += server.sent as u64;
+        lost += server.lost() as u64;
+        jitter_sum += server.jitter();
+    }
+
+    let loss = ((lost as f64) / (sent as f64)) * 100.0f64;
+
+    let jitter = jitter_sum / (entries.len() as f64);
+
+    let average = sum / (entries.len() as f64);
+
+    let sorted = by_rtt(entries, metric);
+
+    let median = {
+        let len = sorted.len();
+
+        if len.is_multiple_of(2)
+        {
+            (
+                metric.rtt(sorted[len / 2 - 1]) +
+                metric.rtt(sorted[len / 2])
+            )
+            / 2.0f64
+        }
+        else { metric.rtt(sorted[len / 2]) }
+    };
+
+    let p95 = percentile(&sorted, 95.0f64, metric);
+    let p99 = percentile(&sorted, 99.0f64, metric);
+
+    let ttl = sorted[0].ttl;
+    let probe = sorted[0].probe;
+
+    Some(CountryStats {
+        country: country.clone(),
+        min,
+        median,
+        p95,
+        p99,
+        average,
+        max,
+        loss,
+        jitter,
+        ttl,
+        samples: entries.len(),
+        score: median * (1.0f64 + (loss / 100.0f64) * loss_penalty),
+        probe
+    })
+}
+
+/// Statistics of every country with at least one server, sorted by score, see [`aggregate`]
+pub fn country_stats(rtts: &HashMap<CountryCode, Vec<Measurement>>, loss_penalty: f64, metric: ServerMetric) -> Vec<CountryStats>
+{
+    let mut intermediate: Vec<CountryStats> = rtts.iter()
+        .filter_map(|(cc, entries)| aggregate(cc, entries, loss_penalty, metric))
+        .collect();
+
+    intermediate.sort_by(|stats1, stats2| {
+        stats1.score.total_cmp(&stats2.score)
+            .then(stats1.min.total_cmp(&stats2.min))
+    });
+
+    intermediate
+}
+
+/// Aligned table of the `top` countries of [`country_stats`] with the lowest median RTT, for a glance
+/// at the terminal. Unlike the output they aren't ranked by score, so lossy countries aren't pushed down.
+pub fn format_ranking(stats: &[CountryStats], top: usize) -> String
+{
+    let mut stats: Vec<&CountryStats> = stats.iter().collect();
+    stats.sort_by(|stats1, stats2| stats1.median.total_cmp(&stats2.median).then(stats1.country.cmp(&stats2.country)));
+    stats.truncate(top);
+
+    let width = stats.iter().map(|stats| stats.country.len()).max().unwrap_or_default().max("Country".len());
+
+    let mut table = format!(
+        "{:>4}  {:<width$}  {:>10}  {:>10}  {:>8}  {:>7}  {:>10}\n",
+        "#", "Country", "Median RTT", "p95 RTT", "Loss %", "Servers", "Score"
+    );
+
+    for (rank, stats) in stats.iter().enumerate()
+    {
+        table += format!(
+            "{:>4}  {:<width$}  {:>10.3}  {:>10.3}  {:>8.2}  {:>7}  {:>10.3}\n",
+            rank + 1, stats.country, stats.median, stats.p95, stats.loss, stats.samples, stats.score
+        ).as_str();
+    }
+
+    table
+}
+
+/// Every server listed under `cc` with all of its replies, whether it's left in the statistics or not,
+/// for explaining where the numbers of a country come from
+pub fn format_server_table(cc: &CountryCode, measured: &[Measurement], summary: &PingSummary) -> String
+{
+    let mut rows: Vec<[String; 7]> = vec![];
+
+    let mut push = |server: &Measurement, status: &str| {
+        let replies: Vec<String> = server.samples.iter().map(|rtt| format!("{:.3}", rtt)).collect();
+
+        rows.push([
+            server.city.clone(), server.ip.clone(), status.to_string(),
+            format!("{:.3}", server.rtt), format!("{:.3}", server.mean()), format!("{:.2}", server.loss()),
+            replies.join(" "),
+        ]);
+    };
+
+    for server in measured { push(server, if server.above_threshold { "slow, kept" } else { "ok" }); }
+    for (_, server) in summary.slow.iter().filter(|(country, _)| country == cc) { push(server, "slow"); }
+
+    let lost = |city: &City, ip: &IP, status: &str| [city.clone(), ip.clone(), status.to_string(), "-".into(), "-".into(), "-".into(), "-".into()];
+
+    rows.extend(summary.unreachable.iter().filter(|(country, _, _)| country == cc).map(|(_, city, ip)| lost(city, ip, "unreachable")));
+    rows.extend(summary.invalid.iter().filter(|(country, _, _)| country == cc).map(|(_, city, ip)| lost(city, ip, "invalid")));
+
+    let header = ["City", "IP", "Status", "Min RTT", "Mean RTT", "Loss %", "Replies"];
+    let mut widths = header.map(str::len);
+
+    for row in rows.iter()
+    {
+        for (width, field) in widths.iter_mut().zip(row.iter())
+        {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+
+    let format_row = |fields: &[&str]| {
+        let [city, ip, status, min, mean, loss, _] = widths;
+        format!(
+            "{:<city$}  {:<ip$}  {:<status$}  {:>min$}  {:>mean$}  {:>loss$}  {}\n",
+            fields[0], fields[1], fields[2], fields[3], fields[4], fields[5], fields[6]
+        )
+    };
+
+    let mut table = format!("{} servers of {}:\n", rows.len(), cc);
+    table += format_row(&header).as_str();
+
+    for row in rows.iter()
+    {
+        table += format_row(&row.each_ref().map(String::as_str)).as_str();
+    }
+
+    table
+}
+
+/// Keeps the first `top` countries of [`country_stats`] along with their servers
+pub fn keep_top(rtts: &mut HashMap<CountryCode, Vec<Measurement>>, top: usize, loss_penalty: f64, metric: ServerMetric)
+{
+    let kept: HashSet<CountryCode> = country_stats(rtts, loss_penalty, metric)
+        .into_iter()
+        .take(top)
+        .map(|stats| stats.country)
+        .collect();
+
+    rtts.retain(|cc, _| kept.contains(cc));
+}
+
+/// Warns about countries with fewer than `min` servers left, their statistics are hardly
+/// more than a single RTT. With `drop` they are removed instead.
+pub fn check_sample_sizes(rtts: &mut HashMap<CountryCode, Vec<Measurement>>, min: usize, drop: bool)
+{
+    let mut small: Vec<(&CountryCode, usize)> = rtts.iter()
+        .filter(|(_, servers)| !servers.is_empty() && servers.len() < min)
+        .map(|(cc, servers)| (cc, servers.len()))
+        .collect();
+    small.sort();
+
+    for (cc, count) in small.iter()
+    {
+        if drop { warn!("Dropping {}, only {} of at least {} servers left", cc, count, min); }
+        else { warn!("{} has only {} of at least {} servers, its statistics are not reliable", cc, count, min); }
+    }
+
+    if drop
+    {
+        rtts.retain(|_, servers| servers.len() >= min);
+    }
+}
+
+/// Codes already warned about as having no name, so every scan of --interval doesn't warn again
+static UNNAMED_WARNED: OnceLock<Mutex<HashSet<CountryCode>>> = OnceLock::new();
+
+/// English name of an ISO 3166 alpha-2 code, None for anything else like `US-EAST`
+fn country_name(cc: &str) -> Option<&'static str>
+{
+    isocountry::CountryCode::for_alpha2_caseless(cc).ok().map(|country| country.name())
+}
+
+/// Per-country statistics, one row per country sorted by score. Reachable % is taken from
+/// the `summary`, of the servers listed under the country before any were moved.
+/// Numbers get `precision` decimals. `with_country_name` adds the name of every country
+/// after its code, blank for codes which aren't ISO 3166 ones, each of them is warned about once per run.
+pub fn generate_csv(rtts: &HashMap<CountryCode, Vec<Measurement>>, style: OutputStyle, precision: usize, loss_penalty: f64, metric: ServerMetric, summary: &PingSummary, with_country_name: bool) -> String
+{
+    let mut csv = String::new();
+
+    let mut headers = vec!["Country", "Min RTT", "Median RTT", "p95 RTT", "p99 RTT", "Average RTT", "Max RTT", "Packet Loss %", "Reachable %", "Jitter (avg server stddev)", "TTL", "Samples", "Score", "Probe"];
+    if with_country_name { headers.insert(1, "Country Name"); }
+
+    let headers: Vec<String> = headers.into_iter().map(|header| style.text(header)).collect();
+    csv += style.row(&headers).as_str();
+
+    let mut unknown = vec![];
+
+    for stats in country_stats(rtts, loss_penalty, metric)
+    {
+        let mut fields = vec![
+            style.text(stats.country.as_str()),
+            style.number(stats.min, precision),
+            style.number(stats.median, precision),
+            style.number(stats.p95, precision),
+            style.number(stats.p99, precision),
+            style.number(stats.average, precision),
+            style.number(stats.max, precision),
+            style.number(stats.loss, precision),
+            summary.reachability(&stats.country).map(|pct| style.number(pct, precision)).unwrap_or_default(),
+            style.number(stats.jitter, precision),
+            style.ttl(stats.ttl),
+            stats.samples.to_string(),
+            style.number(stats.score, precision),
+            style.text(stats.probe.label().as_str()),
+        ];
+
+        if with_country_name
+        {
+            let name = country_name(&stats.country);
+            if name.is_none() { unknown.push(stats.country.clone()); }
+
+            fields.insert(1, style.text(name.unwrap_or_default()));
+        }
+
+        csv += style.row(&fields).as_str();
+    }
+
+    unknown.retain(|cc| UNNAMED_WARNED.get_or_init(Default::default).lock().unwrap().insert(cc.clone()));
+
+    if !unknown.is_empty()
+    {
+        unknown.sort();
+        warn!("No country name known for {}, left blank", unknown.join(", "));
+    }
+
+    csv
+}
+
+fn stats_json(stats: &CountryStats) -> Value
+{
+    json!({
+        "country": stats.country,
+        "min": stats.min,
+        "median": stats.median,
+        "p95": stats.p95,
+        "p99": stats.p99,
+        "average": stats.average,
+        "max": stats.max,
+        "loss": stats.loss,
+        "jitter": stats.jitter,
+        "ttl": stats.ttl,
+        "samples": stats.samples,
+        "score": stats.score,
+        "probe": stats.probe.label(),
+    })
+}
+
+fn server_json(server: &Measurement) -> Value
+{
+    json!({
+        "city": server.city,
+        "ip": server.ip,
+        "rtt": server.rtt,
+        "mean": server.mean(),
+        "loss": server.loss(),
+        "jitter": server.jitter(),
+        "ttl": server.ttl,
+        "above_threshold": server.above_threshold,
+        "probe": server.probe.label(),
+    })
+}
+
+/// Same statistics as the CSV, with every country also listing its servers from the fastest one
+pub fn generate_json(rtts: &HashMap<CountryCode, Vec<Measurement>>, loss_penalty: f64, metric: ServerMetric) -> String
+{
+    let countries: Vec<Value> = country_stats(rtts, loss_penalty, metric)
+        .into_iter()
+        .map(|stats| {
+            let servers: Vec<Value> = by_rtt(&rtts[&stats.country], metric).into_iter().map(server_json).collect();
+
+            let mut country = stats_json(&stats);
+            country["servers"] = Value::Array(servers);
+            country
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&countries).unwrap()
+}
+
+/// Lines of a single country for `--format ndjson`, its statistics or with `per_server` one line
+/// for every server along with its country, from the fastest one. Nothing if the country has no servers.
+pub fn ndjson_lines(country: &CountryCode, servers: &[Measurement], loss_penalty: f64, metric: ServerMetric, per_server: bool) -> String
+{
+    let mut lines = String::new();
+
+    if per_server
+    {
+        for server in by_rtt(servers, metric)
+        {
+            let mut line = serde_json::Map::new();
+            line.insert("country".to_string(), json!(country));
+
+            if let Value::Object(fields) = server_json(server) { line.extend(fields); }
+
+            lines += Value::Object(line).to_string().as_str();
+            lines.push('\n');
+        }
+    }
+    else if let Some(stats) = aggregate(country, servers, loss_penalty, metric)
+    {
+        lines += stats_json(&stats).to_string().as_str();
+        lines.push('\n');
+    }
+
+    lines
+}
+
+/// Every country as [`ndjson_lines`], in the order of [`country_stats`]
+pub fn generate_ndjson(rtts: &HashMap<CountryCode, Vec<Measurement>>, loss_penalty: f64, metric: ServerMetric, per_server: bool) -> String
+{
+    country_stats(rtts, loss_penalty, metric)
+        .into_iter()
+        .map(|stats| ndjson_lines(&stats.country, &rtts[&stats.country], loss_penalty, metric, per_server))
+        .collect()
+}
+
+type StatsField = fn(&CountryStats) -> Rtt;
+
+fn escape_html(text: &str) -> String
+{
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const HTML_HEAD: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>GeoPing results</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: right; }
+th { cursor: pointer; background: #f4f4f4; user-select: none; }
+th:first-child, td:first-child { text-align: left; }
+</style>
+</head>
+<body>
+<h1>GeoPing results</h1>
+<p>Click a column to sort by it. RTT cells are colored from the fastest (green) to the slowest (red) country.</p>
+<table id="stats">
+"##;
+
+const HTML_TAIL: &str = r##"</table>
+<script>
+document.querySelectorAll("#stats th").forEach((th, column) => {
+  th.addEventListener("click", () => {
+    const body = document.querySelector("#stats tbody");
+    const rows = Array.from(body.rows);
+    const ascending = th.dataset.order !== "asc";
+    const key = row => {
+      const text = row.cells[column].textContent;
+      const number = parseFloat(text);
+      return isNaN(number) ? text : number;
+    };
+    rows.sort((a, b) => {
+      const x = key(a), y = key(b);
+      const order = typeof x === "number" && typeof y === "number" ? x - y : String(x).localeCompare(String(y));
+      return ascending ? order : -order;
+    });
+    document.querySelectorAll("#stats th").forEach(other => delete other.dataset.order);
+    th.dataset.order = ascending ? "asc" : "desc";
+    rows.forEach(row => body.appendChild(row));
+  });
+});
+</script>
+</body>
+</html>
+"##;
+
+/// What a run was set up with and what it found, written next to its output so archived results describe themselves
+pub struct RunMetadata<'a>
+{
+    pub started: u64,// unix seconds
+    pub seconds: u64,// the scan took
+    pub hostname: String,// of the machine pinging
+    pub opts: &'a PingOptions,
+    pub probe: Option<ProbeKind>,// None if nothing replied
+    pub servers: u64,// pinged, resumed ones not included
+    pub summary: &'a PingSummary,
+    pub interrupted: bool,
+}
+
+/// `.meta.json` sidecar of an output, see [`RunMetadata`]
+pub fn generate_metadata(meta: &RunMetadata) -> String
+{
+    serde_json::to_string_pretty(&json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "started": meta.started,
+        "seconds": meta.seconds,
+        "hostname": meta.hostname,
+        "count": meta.opts.count,
+        "warmup": meta.opts.warmup,
+        "timeout_ms": meta.opts.timeout.as_millis() as u64,
+        "probe_interval_ms": meta.opts.probe_interval.as_millis() as u64,
+        "payload_bytes": meta.opts.payload_bytes,
+        "probe": meta.probe.map(|probe| probe.label()),
+        "servers": meta.servers,
+        "reachable": meta.summary.reachable,
+        "unreachable": meta.summary.unreachable.len(),
+        "slow": meta.summary.slow.len(),
+        "invalid": meta.summary.invalid.len(),
+        "interrupted": meta.interrupted,
+    })).unwrap()
+}
+
+/// Label value in the exposition format, backslashes, quotes and line feeds are escaped
+fn prometheus_label(value: &str) -> String
+{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Same statistics as the CSV as Prometheus gauges labelled with the country, e.g.
+/// `geoping_rtt_ms{country="DE",stat="median"} 12.3`, for the node_exporter textfile collector
+pub fn generate_prometheus(rtts: &HashMap<CountryCode, Vec<Measurement>>, loss_penalty: f64, metric: ServerMetric, summary: &PingSummary) -> String
+{
+    let stats = country_stats(rtts, loss_penalty, metric);
+
+    let mut prom = String::new();
+
+    let mut gauge = |name: &str, help: &str, samples: Vec<(String, f64)>| {
+        prom += format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name).as_str();
+
+        for (labels, value) in samples
+        {
+            prom += format!("{}{{{}}} {}\n", name, labels, value).as_str();
+        }
+    };
+
+    let country = |stats: &CountryStats| format!("country=\"{}\"", prometheus_label(stats.country.as_str()));
+
+    let rtt_stats: [(&str, StatsField); 6] = [
+        ("min", |stats| stats.min),
+        ("median", |stats| stats.median),
+        ("p95", |stats| stats.p95),
+        ("p99", |stats| stats.p99),
+        ("average", |stats| stats.average),
+        ("max", |stats| stats.max),
+    ];
+
+    gauge("geoping_rtt_ms", "RTT over the servers of a country in milliseconds", stats.iter()
+        .flat_map(|stats| rtt_stats.iter().map(move |(stat, value)| (format!("{},stat=\"{}\"", country(stats), stat), value(stats))))
+        .collect());
+
+    gauge("geoping_packet_loss_ratio", "Share of echo requests without a reply", stats.iter()
+        .map(|stats| (country(stats), stats.loss / 100.0f64))
+        .collect());
+
+    gauge("geoping_reachable_ratio", "Share of the listed servers which replied", stats.iter()
+        .filter_map(|stats| Some((country(stats), summary.reachability(&stats.country)? / 100.0f64)))
+        .collect());
+
+    gauge("geoping_jitter_ms", "Spread of the replies of a single server in milliseconds, averaged over the servers", stats.iter()
+        .map(|stats| (country(stats), stats.jitter))
+        .collect());
+
+    gauge("geoping_servers", "Servers the statistics of a country are based on", stats.iter()
+        .map(|stats| (country(stats), stats.samples as f64))
+        .collect());
+
+    gauge("geoping_score", "Median RTT penalized by packet loss, lower is better", stats.iter()
+        .map(|stats| (country(stats), stats.score))
+        .collect());
+
+    prom
+}
+
+/// A point of every server with coordinates, along with its country and what [`generate_json`] has on it.
+/// Coordinates come from the geo provider, servers it couldn't place are left out.
+pub fn generate_geojson(rtts: &HashMap<CountryCode, Vec<Measurement>>) -> String
+{
+    let mut countries: Vec<&CountryCode> = rtts.keys().collect();
+    countries.sort();
+
+    let mut features = vec![];
+    let mut unplaced = 0;
+
+    for cc in countries
+    {
+        for server in rtts[cc].iter()
+        {
+            let Some((latitude, longitude)) = server.location else {
+                unplaced += 1;
+                continue;
+            };
+
+            let mut properties = serde_json::Map::new();
+            properties.insert("country".to_string(), json!(cc));
+
+            if let Value::Object(fields) = server_json(server) { properties.extend(fields); }
+
+            features.push(json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [longitude, latitude] },
+                "properties": properties,
+            }));
+        }
+    }
+
+    if unplaced > 0
+    {
+        warn!("{} servers have no coordinates, leaving them out of the GeoJSON", unplaced);
+    }
+
+    serde_json::to_string_pretty(&json!({ "type": "FeatureCollection", "features": features })).unwrap()
+}
+
+/// Same statistics as the CSV on a page which needs nothing else, so it can be mailed or hosted as is
+pub fn generate_html(rtts: &HashMap<CountryCode, Vec<Measurement>>, loss_penalty: f64, metric: ServerMetric) -> String
+{
+    let stats = country_stats(rtts, loss_penalty, metric);
+
+    // color scale from the fastest to the slowest country of each RTT column
+    let range = |value: StatsField| {
+        let values = stats.iter().map(value);
+        let min = values.clone().fold(f64::MAX, f64::min);
+        let max = values.fold(f64::MIN, f64::max);
+        (min, max)
+    };
+    let cell = |rtt: Rtt, (min, max): (Rtt, Rtt)| {
+        let position = if max > min { (rtt - min) / (max - min) } else { 0.0f64 };
+        let hue = 120.0f64 * (1.0f64 - position);
+        format!("<td style=\"background: hsl({:.0}, 70%, 80%)\">{:.03}</td>", hue, rtt)
+    };
+
+    let columns: [(&str, StatsField); 6] = [
+        ("Min RTT", |stats| stats.min),
+        ("Median RTT", |stats| stats.median),
+        ("p95 RTT", |stats| stats.p95),
+        ("p99 RTT", |stats| stats.p99),
+        ("Average RTT", |stats| stats.average),
+        ("Max RTT", |stats| stats.max),
+    ];
+    let ranges: Vec<(Rtt, Rtt)> = columns.iter().map(|(_, value)| range(*value)).collect();
+
+    let mut html = String::from(HTML_HEAD);
+
+    html += "<thead><tr><th>Country</th>";
+    for (header, _) in columns.iter()
+    {
+        html += format!("<th>{}</th>", header).as_str();
+    }
+    html += "<th>Packet Loss %</th><th>Jitter</th><th>TTL</th><th>Samples</th><th>Score</th><th>Probe</th></tr></thead>\n<tbody>\n";
+
+    for stats in stats.iter()
+    {
+        html += format!("<tr><td>{}</td>", escape_html(stats.country.as_str())).as_str();
+
+        for ((_, value), range) in columns.iter().zip(ranges.iter())
+        {
+            html += cell(value(stats), *range).as_str();
+        }
+
+        html += format!(
+            "<td>{:.03}</td><td>{:.03}</td><td>{}</td><td>{}</td><td>{:.03}</td><td>{}</td></tr>\n",
+            stats.loss,
+            stats.jitter,
+            stats.ttl.map(|ttl| ttl.to_string()).unwrap_or_default(),
+            stats.samples,
+            stats.score,
+            stats.probe.label()
+        ).as_str();
+    }
+
+    html += "</tbody>\n";
+    html += HTML_TAIL;
+
+    html
+}
+
+/// One row per server, grouped by country and sorted by RTT within it. `with_asn` adds
+/// the organization hosting every server, empty for servers the provider didn't know.
+/// Numbers get `precision` decimals.
+pub fn generate_detailed_csv(rtts: &HashMap<CountryCode, Vec<Measurement>>, style: OutputStyle, precision: usize, with_asn: bool) -> String
+{
+    let mut csv = String::new();
+
+    let mut headers = vec!["Country", "City", "IP", "Min RTT", "Mean RTT", "Packet Loss %", "TTL", "Anomalies", "Above Threshold", "Probe"];
+    if with_asn { headers.push("ASN"); }
+
+    let headers: Vec<String> = headers.into_iter().map(|header| style.text(header)).collect();
+    csv += style.row(&headers).as_str();
+
+    let mut rows: Vec<(&CountryCode, &Measurement)> = rtts.iter()
+        .flat_map(|(cc, servers)| servers.iter().map(move |server| (cc, server)))
+        .collect();
+
+    rows.sort_by(|(cc1, s1), (cc2, s2)| cc1.cmp(cc2).then(s1.rtt.total_cmp(&s2.rtt)));
+
+    for (cc, server) in rows
+    {
+        let mut fields = vec![
+            style.text(cc.as_str()),
+            style.text(server.city.as_str()),
+            style.text(server.ip.as_str()),
+            style.number(server.rtt, precision),
+            style.number(server.mean(), precision),
+            style.number(server.loss(), precision),
+            style.ttl(server.ttl),
+            server.above_threshold.to_string(),
+            style.text(server.probe.label().as_str()),
+        ];
+        if with_asn { fields.push(style.text(server.asn.as_deref().unwrap_or_default())); }
+
+        csv += style.row(&fields).as_str();
+    }
+
+    csv
+}
+
+/// Servers which didn't reply, were too slow or couldn't be pinged at all, for cleaning up the server files
+pub fn generate_unreachable_csv(summary: &PingSummary, style: OutputStyle) -> String
+{
+    let mut csv = String::new();
+
+    csv += style.row(&["Country", "City", "IP", "Reason"].map(|header| style.text(header))).as_str();
+
+    let unreachable = summary.unreachable.iter().map(|(cc, city, ip)| (cc, city, ip, "unreachable"));
+    let slow = summary.slow.iter().map(|(cc, server)| (cc, &server.city, &server.ip, "slow"));
+    let invalid = summary.invalid.iter().map(|(cc, city, ip)| (cc, city, ip, "invalid"));
+
+    for (cc, city, ip, reason) in unreachable.chain(slow).chain(invalid)
+    {
+        csv += style.row(&[
+            style.text(cc.as_str()),
+            style.text(city.as_str()),
+            style.text(ip.as_str()),
+            style.text(reason),
+        ]).as_str();
+    }
+
+    csv
+}
+
+/// Creates missing parent directories and replaces whatever was at `path`, with the extension of `compression`
+/// appended. The content goes to a temporary file next to it first, which is renamed over `path` once it's
+/// all written, so a run dying halfway leaves the previous file as it was instead of a truncated one.
+pub fn write_output(path: &Path, content: &str, compression: Compression) -> Result<(), GeopingError>
+{
+    let path = compression.path(path);
+    let path = path.as_path();
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let write = || -> io::Result<()> {
+        if let Some(parent) = path.parent()
+        {
+            if !parent.as_os_str().is_empty()
+            {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        file.write_all(compression.encode(content.as_bytes())?.as_slice())?;
+        file.sync_all()?;
+
+        fs::rename(&temp_path, path)
+    };
+
+    write().map_err(|source| {
+        let _ = fs::remove_file(&temp_path);
+        GeopingError::Write { path: path.to_path_buf(), source }
+    })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn server(ip: &str, rtt: Rtt, received: usize, sent: u16) -> Measurement
+    {
+        Measurement {
+            city: format!("City of {}", ip),
+            ip: ip.to_string(),
+            rtt,
+            samples: vec![rtt; received],
+            sent,
+            ttl: None,
+            asn: None,
+            location: None,
+            above_threshold: false,
+            probe: ProbeKind::Icmp,
+        }
+    }
+
+    #[test]
+    fn countries_without_servers_are_left_out()
+    {
+        assert!(aggregate(&"DE".to_string(), &[], 1.0, ServerMetric::Min).is_none());
+
+        let rtts = HashMap::from([
+            ("DE".to_string(), vec![]),
+            ("PL".to_string(), vec![server("192.0.2.2", 20.0, 4, 4)]),
+        ]);
+        let csv = generate_csv(&rtts, OutputStyle::Csv, 3, 1.0, ServerMetric::Min, &PingSummary::default(), false);
+
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].starts_with("PL,"));
+
+        for sentinel in ["NaN", "inf", &f64::MAX.to_string()]
+        {
+            assert!(!csv.contains(sentinel), "{} in {}", sentinel, csv);
+        }
+    }
+
+    #[test]
+    fn aggregates_an_even_number_of_servers()
+    {
+        let servers = [
+            server("192.0.2.1", 40.0, 4, 4),
+            server("192.0.2.2", 10.0, 4, 4),
+            server("192.0.2.3", 30.0, 2, 4),
+            server("192.0.2.4", 20.0, 4, 4),
+        ];
+
+        let stats = aggregate(&"DE".to_string(), &servers, 2.0, ServerMetric::Min).unwrap();
+
+        // the median of an even count is the mean of the middle two
+        assert_eq!(stats.median, 25.0);
+        assert_eq!((stats.min, stats.max, stats.average), (10.0, 40.0, 25.0));
+        assert_eq!(stats.p95, 40.0);
+        assert_eq!(stats.loss, 12.5);
+        assert_eq!(stats.score, 25.0 * (1.0 + 0.125 * 2.0));
+    }
+
+    #[test]
+    fn aggregates_an_odd_number_of_servers()
+    {
+        let servers = [server("192.0.2.1", 30.0, 4, 4), server("192.0.2.2", 10.0, 4, 4), server("192.0.2.3", 20.0, 4, 4)];
+
+        let stats = aggregate(&"DE".to_string(), &servers, 1.0, ServerMetric::Min).unwrap();
+
+        assert_eq!(stats.median, 20.0);
+        assert_eq!(stats.p95, 30.0);
+        assert_eq!(stats.loss, 0.0);
+        assert_eq!(stats.jitter, 0.0);
+    }
+
+    #[test]
+    fn aggregates_the_mean_rtt_of_every_server_with_the_mean_metric()
+    {
+        let mut spread = server("192.0.2.1", 10.0, 2, 2);
+        spread.samples = vec![10.0, 30.0];
+
+        let servers = [spread, server("192.0.2.2", 40.0, 2, 2)];
+
+        let min = aggregate(&"DE".to_string(), &servers, 1.0, ServerMetric::Min).unwrap();
+        let mean = aggregate(&"DE".to_string(), &servers, 1.0, ServerMetric::Mean).unwrap();
+
+        assert_eq!((min.min, min.median), (10.0, 25.0));
+        assert_eq!((mean.min, mean.median), (20.0, 30.0));
+        assert_eq!(mean.jitter, 5.0);
+    }
+
+    #[test]
+    fn ranking_lists_the_lowest_medians_whatever_the_score()
+    {
+        // DE is faster but loses half of its packets, so it scores 22.5 against 20 of PL
+        let rtts = HashMap::from([
+            ("DE".to_string(), vec![server("192.0.2.1", 15.0, 2, 4)]),
+            ("PL".to_string(), vec![server("192.0.2.2", 20.0, 4, 4)]),
+            ("FR".to_string(), vec![server("192.0.2.3", 30.0, 4, 4)]),
+        ]);
+
+        let stats = country_stats(&rtts, 1.0, ServerMetric::Min);
+        let by_score: Vec<&str> = stats.iter().map(|stats| stats.country.as_str()).collect();
+
+        let ranking = format_ranking(&stats, 2);
+        let by_median: Vec<&str> = ranking.lines().skip(1).map(|line| line.split_whitespace().nth(1).unwrap()).collect();
+
+        assert_eq!(by_score, ["PL", "DE", "FR"]);
+        assert_eq!(by_median, ["DE", "PL"]);
+    }
+
+    #[test]
+    fn generating_leaves_the_input_order_alone()
+    {
+        let servers = vec![server("192.0.2.1", 30.0, 4, 4), server("192.0.2.2", 10.0, 4, 4), server("192.0.2.3", 20.0, 3, 4)];
+        let rtts = HashMap::from([("DE".to_string(), servers.clone())]);
+
+        let order = |servers: &[Measurement]| servers.iter().map(|server| server.ip.clone()).collect::<Vec<_>>();
+
+        aggregate(&"DE".to_string(), &servers, 1.0, ServerMetric::Min).unwrap();
+        let first = generate_csv(&rtts, OutputStyle::Csv, 3, 1.0, ServerMetric::Min, &PingSummary::default(), false);
+        let second = generate_csv(&rtts, OutputStyle::Csv, 3, 1.0, ServerMetric::Min, &PingSummary::default(), false);
+
+        assert_eq!(order(&servers), ["192.0.2.1", "192.0.2.2", "192.0.2.3"]);
+        assert_eq!(order(&rtts["DE"]), ["192.0.2.1", "192.0.2.2", "192.0.2.3"]);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn country_names_follow_the_codes_and_unknown_ones_are_warned_about_once()
+    {
+        let rtts = HashMap::from([
+            ("DE".to_string(), vec![server("192.0.2.1", 10.0, 4, 4)]),
+            ("US-EAST".to_string(), vec![server("192.0.2.2", 20.0, 4, 4)]),
+        ]);
+
+        let csv = generate_csv(&rtts, OutputStyle::Csv, 3, 1.0, ServerMetric::Min, &PingSummary::default(), true);
+        let rows: Vec<&str> = csv.lines().collect();
+
+        assert!(rows[0].starts_with("Country,Country Name,Min RTT"));
+        assert!(rows[1].starts_with("DE,Germany,"), "{}", rows[1]);
+        assert!(rows[2].starts_with("US-EAST,,"), "{}", rows[2]);
+
+        let warned = UNNAMED_WARNED.get().unwrap().lock().unwrap();
+
+        assert!(warned.contains("US-EAST"));
+        assert!(!warned.contains("DE"));
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use log::warn;
+use serde_json::{json, Value};
+use crate::CountryCode;
+use crate::ping::{Measurement, ProbeKind};
+
+/// Results of already pinged countries, one JSON line per country, so a crashed
+/// or interrupted scan can be resumed without pinging them again
+pub struct PartialWriter
+{
+    file: File,
+}
+
+impl PartialWriter
+{
+    /// Starts from scratch unless `resume` is set, then new countries are appended
+    pub fn open(path: &Path, resume: bool) -> io::Result<PartialWriter>
+    {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(path)?;
+
+        Ok(PartialWriter { file })
+    }
+
+    pub fn append(&mut self, cc: &CountryCode, servers: &[Measurement]) -> io::Result<()>
+    {
+        let servers: Vec<Value> = servers.iter()
+            .map(|server| json!({
+                "city": server.city,
+                "ip": server.ip,
+                "samples": server.samples,
+                "sent": server.sent,
+                "ttl": server.ttl,
+                "above_threshold": server.above_threshold,
+                "probe": server.probe.label(),
+            }))
+            .collect();
+
+        let line = json!({ "country": cc, "servers": servers });
+
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()
+    }
+}
+
+fn parse_measurement(server: &Value) -> Option<Measurement>
+{
+    let samples: Vec<f64> = server["samples"].as_array()?
+        .iter()
+        .map(|rtt| rtt.as_f64())
+        .collect::<Option<_>>()?;
+
+    Some(Measurement {
+        city: server["city"].as_str()?.to_string(),
+        ip: server["ip"].as_str()?.to_string(),
+        rtt: samples.iter().cloned().reduce(f64::min)?,
+        samples,
+        sent: server["sent"].as_u64()? as u16,
+        ttl: server["ttl"].as_u64().map(|ttl| ttl as u8),
+        asn: None,
+        location: None,
+        above_threshold: server["above_threshold"].as_bool().unwrap_or_default(),
+        probe: server["probe"].as_str().and_then(ProbeKind::parse).unwrap_or_default(),
+    })
+}
+
+/// Countries written by [`PartialWriter`], countries nobody replied in are kept with no servers.
+/// A line cut off by a crash is skipped, so that country is pinged again.
+pub fn load_partial(path: &Path) -> HashMap<CountryCode, Vec<Measurement>>
+{
+    let mut rtts = HashMap::new();
+
+    let Ok(content) = fs::read_to_string(path) else { return rtts; };
+
+    for (i, line) in content.lines().enumerate()
+    {
+        let parsed = serde_json::from_str::<Value>(line).ok().and_then(|json| {
+            let cc = json["country"].as_str()?.to_string();
+            let servers = json["servers"].as_array()?
+                .iter()
+                .map(parse_measurement)
+                .collect::<Option<Vec<_>>>()?;
+
+            Some((cc, servers))
+        });
+
+        match parsed
+        {
+            Some((cc, servers)) => { rtts.insert(cc, servers); }
+            None => { warn!("Skipping line {} of {}, it's incomplete", i + 1, path.to_string_lossy()); }
+        }
+    }
+
+    rtts
+}
@@ -0,0 +1,1211 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::io;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use ipnet::IpNet;
+use futures::{stream, StreamExt};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use indicatif::{MultiProgress, ProgressBar};
+use log::{info, warn};
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use surge_ping::{Client, Config, ICMP, IcmpPacket, PingIdentifier, PingSequence};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio::sync::{watch, OnceCell};
+use tokio::time::{sleep, timeout};
+use crate::{City, CountryCode, IP, Rtt};
+use crate::error::GeopingError;
+use crate::progress;
+use crate::progress::Verbosity;
+
+/// Replies of a single server
+#[derive(Clone, Debug)]
+pub struct Measurement
+{
+    pub city: City,
+    pub ip: IP,
+    pub rtt: Rtt,// lowest of the received replies
+    pub samples: Vec<Rtt>,// RTT of every received reply, in order
+    pub sent: u16,
+    pub ttl: Option<u8>,// of the fastest reply, surge-ping only exposes it for IPv4
+    pub asn: Option<String>,// organization hosting the server, e.g. "AS15169 Google LLC", filled by enrich_locations
+    pub location: Option<(f64, f64)>,// latitude and longitude, filled by enrich_locations
+    pub above_threshold: bool,// fastest reply slower than the max acceptable RTT, only kept with keep_slow
+    pub probe: ProbeKind,
+}
+
+/// How the RTT of a server was measured, TCP handshakes take longer than echo replies
+/// as the server's TCP stack answers them, so the two aren't comparable
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProbeKind
+{
+    #[default]
+    Icmp,
+    Tcp(u16),// port connected to
+}
+
+impl ProbeKind
+{
+    /// "icmp" or "tcp/443"
+    pub fn label(&self) -> String
+    {
+        match self
+        {
+            ProbeKind::Icmp => "icmp".to_string(),
+            ProbeKind::Tcp(port) => format!("tcp/{}", port),
+        }
+    }
+
+    pub fn parse(label: &str) -> Option<ProbeKind>
+    {
+        match label.strip_prefix("tcp/")
+        {
+            Some(port) => port.parse().ok().map(ProbeKind::Tcp),
+            None if label == "icmp" => Some(ProbeKind::Icmp),
+            None => None,
+        }
+    }
+}
+
+/// What to measure instead when no ICMP socket can be opened, e.g. without CAP_NET_RAW
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fallback
+{
+    /// Time TCP handshakes to port 443
+    Tcp,
+}
+
+pub const TCP_FALLBACK_PORT: u16 = 443;
+
+impl Measurement
+{
+    pub fn received(&self) -> u16
+    {
+        self.samples.len() as u16
+    }
+
+    pub fn lost(&self) -> u16
+    {
+        self.sent - self.received()
+    }
+
+    /// % of echo requests without reply
+    pub fn loss(&self) -> f64
+    {
+        ((self.lost() as f64) / (self.sent as f64)) * 100.0f64
+    }
+
+    /// Mean of the received replies, less optimistic than the lowest one
+    pub fn mean(&self) -> Rtt
+    {
+        self.samples.iter().sum::<f64>() / (self.samples.len() as f64)
+    }
+
+    /// Population standard deviation of the replies, 0 with a single reply
+    pub fn jitter(&self) -> Rtt
+    {
+        if self.samples.len() < 2 { return 0.0f64; }
+
+        let n = self.samples.len() as f64;
+
+        let mean = self.mean();
+        let variance = self.samples.iter().map(|rtt| (rtt - mean).powi(2)).sum::<f64>() / n;
+
+        variance.sqrt()
+    }
+}
+
+/// Address families pinged, the others are skipped
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpVersion
+{
+    #[value(name = "4")]
+    V4,
+    #[value(name = "6")]
+    V6,
+    Both,
+}
+
+impl IpVersion
+{
+    pub fn allows(&self, addr: &IpAddr) -> bool
+    {
+        match self
+        {
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+            IpVersion::Both => true,
+        }
+    }
+}
+
+/// IPv4 or IPv6 literal, None for anything else like hostnames
+pub fn parse_ip(ip: &str) -> Option<IpAddr>
+{
+    if let Ok(addr) = Ipv4Addr::from_str(ip) { Some(IpAddr::V4(addr)) }
+    else if let Ok(addr) = Ipv6Addr::from_str(ip) { Some(IpAddr::V6(addr)) }
+    else { None }
+}
+
+/// Resolves a hostname to its first A/AAAA record of an allowed family, every name is looked up once per run.
+/// Internationalized names like münchen.example are looked up in their punycode form, xn--mnchen-3ya.example.
+async fn resolve_host(host: &str, resolved: &Mutex<HashMap<String, Option<IpAddr>>>, opts: &PingOptions, multi: &MultiProgress) -> Option<IpAddr>
+{
+    if let Some(addr) = resolved.lock().unwrap().get(host) { return *addr; }
+
+    // getaddrinfo takes "12.3" for 12.0.0.3, but it's a broken IP rather than a hostname
+    if host.split('.').all(|label| label.chars().all(|c| c.is_ascii_digit()))
+    {
+        multi.suspend(|| warn!("Could not resolve {}, it's neither an IP nor a hostname", host));
+        resolved.lock().unwrap().insert(host.to_string(), None);
+        return None;
+    }
+
+    let ascii = match idna::domain_to_ascii(host)
+    {
+        Ok(ascii) => ascii,
+        Err(_) => {
+            multi.suspend(|| warn!("Could not resolve {}, it's not a valid domain name", host));
+            resolved.lock().unwrap().insert(host.to_string(), None);
+            return None;
+        }
+    };
+
+    if ascii != host && opts.verbosity == Verbosity::Verbose
+    {
+        multi.suspend(|| info!("Resolving {} as {}", host, ascii));
+    }
+
+    let addr = match lookup_host((ascii.as_str(), 0)).await
+    {
+        Ok(addrs) => addrs.map(|addr| addr.ip()).find(|addr| opts.ip_version.allows(addr)),
+        Err(err) => {
+            multi.suspend(|| warn!("Could not resolve {}: {}", host, err));
+            None
+        }
+    };
+
+    resolved.lock().unwrap().insert(host.to_string(), addr);
+
+    addr
+}
+
+/// How servers are pinged by [`ping_servers`]
+pub struct PingOptions
+{
+    pub count: u16,// echo requests per server
+    pub warmup: u16,// echo requests sent before those, their replies are left out
+    pub probe_interval: Duration,// pause between two echo requests to the same server
+    pub pps: Option<NonZeroU32>,// requests sent per second over all servers in flight
+    pub payload_bytes: usize,// of every echo request
+    pub timeout: Duration,// per reply
+    pub server_budget: Option<Duration>,// per server over all of its requests, those it leaves no time for are lost
+    pub max_acceptable_rtt: Option<Rtt>,// ms, servers whose fastest reply is slower are left out
+    pub keep_slow: bool,// keep those flagged as above_threshold instead
+    pub concurrency: usize,// servers pinged at once within a country
+    pub server_concurrency: HashMap<CountryCode, usize>,// in place of `concurrency` for the countries asking for another one
+    pub country_concurrency: usize,// countries pinged at once
+    pub dedupe: bool,// ping every IP once, even if listed a few times
+    pub shuffle: Option<u64>,// seed to ping countries and their servers in, listed order otherwise
+    pub ip_version: IpVersion,
+    pub exclude: Vec<IpNet>,// ranges which are never pinged, e.g. management subnets
+    pub fallback: Option<Fallback>,// used if ICMP sockets can't be opened, fails otherwise
+    pub tcp_port: Option<u16>,// time TCP connects to this port instead of pinging
+    pub bind: Option<IpAddr>,// local address requests are sent from, only servers of its family can be pinged
+    pub verbosity: Verbosity,
+}
+
+/// Time to wait for the next reply of a server whose first request went out at `started`,
+/// None once its budget is spent
+fn probe_timeout(opts: &PingOptions, started: Instant) -> Option<Duration>
+{
+    match opts.server_budget
+    {
+        Some(budget) => budget.checked_sub(started.elapsed()).filter(|left| !left.is_zero()).map(|left| left.min(opts.timeout)),
+        None => Some(opts.timeout),
+    }
+}
+
+/// TTL of the IP header, IPv6 replies come without their hop limit
+fn reply_ttl(packet: &IcmpPacket) -> Option<u8>
+{
+    match packet
+    {
+        IcmpPacket::V4(packet) => packet.get_ttl(),
+        IcmpPacket::V6(_) => None,
+    }
+}
+
+/// What came back from a single server
+#[derive(Clone, Debug, Default)]
+pub struct Replies
+{
+    pub samples: Vec<Rtt>,// RTT of every reply which came back in time, empty if the server is unreachable
+    pub ttl: Option<u8>,// of the fastest reply
+}
+
+/// What a [`Probe`] shares with the rest of a [`ping_servers`] run
+pub struct ProbeContext<'a>
+{
+    pub opts: &'a PingOptions,
+    limiter: Option<&'a DefaultDirectRateLimiter>,
+    multi: &'a MultiProgress,
+}
+
+impl ProbeContext<'_>
+{
+    /// Waits until request `i` of a server whose first request went out at `started` may be sent and
+    /// returns how long to wait for its reply, None once the server's budget is spent
+    pub async fn next_request(&self, i: u16, started: Instant) -> Option<Duration>
+    {
+        if i > 0 && !self.opts.probe_interval.is_zero()
+        {
+            sleep(self.opts.probe_interval).await;
+        }
+
+        if let Some(limiter) = self.limiter
+        {
+            limiter.until_ready().await;
+        }
+
+        probe_timeout(self.opts, started)
+    }
+
+    /// Logs without garbling the progress bars
+    pub fn log(&self, message: String)
+    {
+        self.multi.suspend(|| info!("{}", message));
+    }
+}
+
+/// Measures a single server, [`ping_servers`] runs it for every one of them. [`IcmpProbe`] and [`TcpProbe`]
+/// measure over the network, `ScriptedProbe` of the `test-util` feature replays given RTTs to run the rest without it.
+#[async_trait]
+pub trait Probe: Send + Sync
+{
+    /// What the servers are marked as measured with
+    fn kind(&self) -> ProbeKind;
+
+    /// Sends `ctx.opts.warmup + ctx.opts.count` requests to `addr`, `ident` is unique among the servers
+    /// in flight. Replies to the warmup requests and ones slower than `ctx.opts.timeout` are left out.
+    async fn probe(&self, addr: IpAddr, ident: u16, ctx: &ProbeContext<'_>) -> Replies;
+}
+
+/// Pings a server `opts.count` times, one echo request after another, after `opts.warmup` ones
+/// whose replies only warm up ARP / neighbor caches on the way and are left out. surge-ping hands
+/// every request only the reply with its own sequence and drops the rest, so the sequences which
+/// didn't come back in time are all there is to tell, `--verbose` logs them.
+/// A request which times out is lost on its own, the rest are still sent unless `opts.server_budget` runs out.
+async fn ping_server(client: &Client, addr: IpAddr, ident: PingIdentifier, ctx: &ProbeContext<'_>) -> Replies
+{
+    let opts = ctx.opts;
+    let mut pinger = client.pinger(addr, ident).await;
+
+    let max_rtt = opts.timeout.as_secs_f64() * 1000.0f64;
+
+    let payload = vec![0u8; opts.payload_bytes];
+
+    let mut samples = vec![];
+    let mut best: Option<(Rtt, Option<u8>)> = None;
+    let mut lost = vec![];
+
+    let started = Instant::now();
+
+    for i in 0..opts.warmup + opts.count
+    {
+        let Some(wait) = ctx.next_request(i, started).await else { break; };
+
+        match timeout(wait, pinger.ping(PingSequence(i), &payload)).await
+        {
+            Ok(ping_result) => {
+                if let Ok((packet, duration)) = ping_result {
+
+                    let rtt = (duration.as_nanos() as f64) / 1_000_000.0f64;
+
+                    if rtt < max_rtt && i >= opts.warmup
+                    {
+                        samples.push(rtt);
+
+                        if best.is_none_or(|(best_rtt, _)| rtt < best_rtt)
+                        {
+                            best = Some((rtt, reply_ttl(&packet)));
+                        }
+
+                        continue;
+                    }
+                }
+            }
+            Err(_) => { /* timed out, counts as lost */ }
+        }
+
+        if i >= opts.warmup { lost.push(i); }
+    }
+
+    if opts.verbosity == Verbosity::Verbose && !lost.is_empty() && !samples.is_empty()
+    {
+        ctx.log(format!("{} lost sequences {:?}", addr, lost));
+    }
+
+    Replies { samples, ttl: best.and_then(|(_, ttl)| ttl) }
+}
+
+/// Connects from `bind` if given, from whatever the routing table picks otherwise
+async fn connect(addr: IpAddr, port: u16, bind: Option<IpAddr>) -> io::Result<TcpStream>
+{
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+
+    if let Some(bind) = bind
+    {
+        socket.bind(SocketAddr::new(bind, 0))?;
+    }
+
+    socket.connect(SocketAddr::new(addr, port)).await
+}
+
+/// Times `opts.count` TCP handshakes with `port` after `opts.warmup` ones, the unprivileged way
+/// to estimate an RTT. A refused connection took a round trip as well, so it counts as a reply.
+async fn connect_server(addr: IpAddr, port: u16, bind: Option<IpAddr>, ctx: &ProbeContext<'_>) -> Replies
+{
+    let opts = ctx.opts;
+    let max_rtt = opts.timeout.as_secs_f64() * 1000.0f64;
+
+    let mut samples = vec![];
+
+    let started = Instant::now();
+
+    for i in 0..opts.warmup + opts.count
+    {
+        let Some(wait) = ctx.next_request(i, started).await else { break; };
+
+        let start = Instant::now();
+
+        let replied = match timeout(wait, connect(addr, port, bind)).await
+        {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => err.kind() == io::ErrorKind::ConnectionRefused,
+            Err(_) => false,
+        };
+
+        let rtt = (start.elapsed().as_nanos() as f64) / 1_000_000.0f64;
+
+        if replied && rtt < max_rtt && i >= opts.warmup
+        {
+            samples.push(rtt);
+        }
+    }
+
+    Replies { samples, ttl: None }
+}
+
+/// ICMP echo requests, needs root or CAP_NET_RAW to open its socket
+pub struct IcmpProbe
+{
+    client: Client,
+}
+
+impl IcmpProbe
+{
+    /// Sends from `bind` if given, which also makes it an IPv6 socket for an IPv6 address
+    pub fn new(bind: Option<IpAddr>) -> Result<IcmpProbe, GeopingError>
+    {
+        let config = match bind
+        {
+            Some(bind) => Config::builder()
+                .bind(SocketAddr::new(bind, 0))
+                .kind(if bind.is_ipv6() { ICMP::V6 } else { ICMP::V4 })
+                .build(),
+            None => Config::default(),
+        };
+
+        Ok(IcmpProbe { client: Client::new(&config).map_err(GeopingError::PingClient)? })
+    }
+}
+
+#[async_trait]
+impl Probe for IcmpProbe
+{
+    fn kind(&self) -> ProbeKind
+    {
+        ProbeKind::Icmp
+    }
+
+    async fn probe(&self, addr: IpAddr, ident: u16, ctx: &ProbeContext<'_>) -> Replies
+    {
+        ping_server(&self.client, addr, PingIdentifier(ident), ctx).await
+    }
+}
+
+/// Timed TCP handshakes with `port`, works unprivileged
+pub struct TcpProbe
+{
+    pub port: u16,
+    pub bind: Option<IpAddr>,// local address connected from
+}
+
+#[async_trait]
+impl Probe for TcpProbe
+{
+    fn kind(&self) -> ProbeKind
+    {
+        ProbeKind::Tcp(self.port)
+    }
+
+    async fn probe(&self, addr: IpAddr, _ident: u16, ctx: &ProbeContext<'_>) -> Replies
+    {
+        connect_server(addr, self.port, self.bind, ctx).await
+    }
+}
+
+/// Answers every request with the RTT scripted for its address, None or a missing one is a timeout,
+/// as do all requests to addresses which aren't scripted. Pacing and the server budget still apply.
+#[cfg(any(test, feature = "test-util"))]
+pub struct ScriptedProbe
+{
+    pub replies: HashMap<IpAddr, Vec<Option<Rtt>>>,// ms, one per request, warmup ones first
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[async_trait]
+impl Probe for ScriptedProbe
+{
+    fn kind(&self) -> ProbeKind
+    {
+        ProbeKind::Icmp
+    }
+
+    async fn probe(&self, addr: IpAddr, _ident: u16, ctx: &ProbeContext<'_>) -> Replies
+    {
+        let opts = ctx.opts;
+        let max_rtt = opts.timeout.as_secs_f64() * 1000.0f64;
+        let script = self.replies.get(&addr).map(Vec::as_slice).unwrap_or_default();
+
+        let mut samples = vec![];
+
+        let started = Instant::now();
+
+        for i in 0..opts.warmup + opts.count
+        {
+            if ctx.next_request(i, started).await.is_none() { break; }
+
+            if let Some(Some(rtt)) = script.get(i as usize)
+            {
+                if *rtt < max_rtt && i >= opts.warmup { samples.push(*rtt); }
+            }
+        }
+
+        Replies { samples, ttl: None }
+    }
+}
+
+/// Probe the options ask for: TCP connects with `opts.tcp_port`, ICMP otherwise, both sent from `opts.bind`.
+/// Fails if no ICMP socket can be opened, unless `opts.fallback` says what to measure instead.
+pub fn open_probe(opts: &PingOptions) -> Result<Box<dyn Probe>, GeopingError>
+{
+    match opts.tcp_port
+    {
+        Some(port) => {
+            info!("Timing TCP connects to port {}", port);
+            Ok(Box::new(TcpProbe { port, bind: opts.bind }))
+        }
+        None => match IcmpProbe::new(opts.bind)
+        {
+            Ok(probe) => Ok(Box::new(probe)),
+            Err(err) if opts.fallback == Some(Fallback::Tcp) => {
+                warn!("Could not open an ICMP socket ({}), timing TCP connects to port {} instead", err, TCP_FALLBACK_PORT);
+                Ok(Box::new(TcpProbe { port: TCP_FALLBACK_PORT, bind: opts.bind }))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// How many listed servers replied and which ones didn't, over the whole run
+#[derive(Default, Debug)]
+pub struct PingSummary
+{
+    pub reachable: u64,
+    pub unreachable: Vec<(CountryCode, City, IP)>,// no reply came back in time
+    pub slow: Vec<(CountryCode, Measurement)>,// replied, but slower than the max acceptable RTT
+    pub invalid: Vec<(CountryCode, City, IP)>,// neither an IP nor a resolvable hostname
+    pub attempted: HashMap<CountryCode, u64>,// servers of every country pinged or found invalid
+    pub replied: HashMap<CountryCode, u64>,// of those, servers which replied at least once
+}
+
+impl PingSummary
+{
+    /// % of the servers listed under `cc` which replied, counting invalid ones as not replying.
+    /// None for countries which weren't pinged in this run, e.g. resumed ones.
+    pub fn reachability(&self, cc: &CountryCode) -> Option<f64>
+    {
+        let attempted = *self.attempted.get(cc).filter(|attempted| **attempted > 0)?;
+        let replied = self.replied.get(cc).copied().unwrap_or_default();
+
+        Some((replied as f64) / (attempted as f64) * 100.0f64)
+    }
+}
+
+/// Results of a single country
+struct CountryScan
+{
+    cc: CountryCode,
+    measured: Vec<Measurement>,
+    unreachable: Vec<(City, IP)>,
+    slow: Vec<Measurement>,
+    invalid: Vec<(City, IP)>,
+    complete: bool,// false if the run was stopped before every server got pinged
+}
+
+/// State shared by all countries of a single [`ping_servers`] run
+struct Scan<'a>
+{
+    opts: &'a PingOptions,
+    probe: &'a dyn Probe,
+    multi: MultiProgress,
+    total_bar: ProgressBar,
+    // identifiers are handed out sequentially for the whole run, starting at a random one so
+    // runs side by side on the same host don't read each other's replies. surge-ping matches
+    // replies on the address too, so after wrapping around an identifier can only be mistaken
+    // for an earlier one if that pinger is still in flight to the same address, which takes
+    // more than 65536 pingers in flight at once
+    next_ident: AtomicU16,
+    resolved: Mutex<HashMap<String, Option<IpAddr>>>,
+    // replies of every IP pinged so far, only kept with --dedupe. Countries in flight
+    // at the same time wait for the one pinging a shared IP instead of pinging it again
+    pinged: Mutex<HashMap<IP, Arc<OnceCell<Replies>>>>,
+    // once set no more servers are pinged and the ones in flight are cut short
+    stop: &'a watch::Receiver<bool>,
+    // shared by every server in flight, a request goes out once it lets it
+    limiter: Option<DefaultDirectRateLimiter>,
+}
+
+impl Scan<'_>
+{
+    async fn probe(&self, addr: IpAddr, ident: u16) -> Replies
+    {
+        let ctx = ProbeContext { opts: self.opts, limiter: self.limiter.as_ref(), multi: &self.multi };
+
+        self.probe.probe(addr, ident, &ctx).await
+    }
+
+    async fn ping_country(&self, cc: CountryCode, cities: Vec<(City, IP)>) -> CountryScan
+    {
+        let opts = self.opts;
+        let multi = &self.multi;
+
+        if *self.stop.borrow()
+        {
+            return CountryScan { cc, measured: vec![], unreachable: vec![], slow: vec![], invalid: vec![], complete: false };
+        }
+
+        let mut jobs = vec![];
+        let mut queued = HashSet::new();
+        let mut invalid = vec![];
+        let mut filtered = 0;
+        let mut excluded = 0;
+
+        for (city, ip) in cities.iter()
+        {
+            if opts.dedupe && !queued.insert(ip) { continue; }
+
+            let addr = match parse_ip(ip.as_str())
+            {
+                Some(addr) => Some(addr),
+                None => resolve_host(ip.as_str(), &self.resolved, opts, multi).await,
+            };
+
+            match addr
+            {
+                Some(addr) if !opts.ip_version.allows(&addr) => { filtered += 1; }
+                Some(addr) if opts.exclude.iter().any(|net| net.contains(&addr)) => { excluded += 1; }
+                Some(addr) => {
+                    let ident = self.next_ident.fetch_add(1, Ordering::Relaxed);
+                    jobs.push((city, ip, addr, ident));
+                }
+                None => {
+                    multi.suspend(|| warn!("Skipping city {} in {}, \"{}\" is neither an IP address nor a resolvable hostname", city, cc, ip));
+                    invalid.push((city.clone(), ip.clone()));
+                }
+            }
+        }
+
+        if filtered > 0
+        {
+            multi.suspend(|| info!("Skipped {} addresses in {} not matching --ip-version", filtered, cc));
+        }
+
+        if excluded > 0
+        {
+            multi.suspend(|| info!("Skipped {} addresses in {} within --exclude-cidr", excluded, cc));
+        }
+
+        // skipped entries count as done too
+        self.total_bar.inc((cities.len() - jobs.len()) as u64);
+
+        let concurrency = match opts.server_concurrency.get(&cc)
+        {
+            Some(concurrency) => {
+                multi.suspend(|| info!("Pinging {} servers of {} at once, as its server file asks", concurrency, cc));
+                *concurrency
+            }
+            None => opts.concurrency,
+        };
+
+        let country_bar = multi.add(progress::bar(jobs.len() as u64, cc.as_str(), opts.verbosity));
+
+        let mut results = stream::iter(jobs)
+            .map(|(city, ip, addr, ident)| async move {
+                // checked once the job gets its turn, not when it's queued
+                if *self.stop.borrow() { return (city, ip, None); }
+
+                let replies = async {
+                    if opts.dedupe
+                    {
+                        let cell = self.pinged.lock().unwrap().entry(ip.clone()).or_default().clone();
+                        cell.get_or_init(|| self.probe(addr, ident)).await.clone()
+                    }
+                    else { self.probe(addr, ident).await }
+                };
+
+                // a server cut short counts as not pinged, so the country is left unfinished
+                tokio::select! {
+                    replies = replies => (city, ip, Some(replies)),
+                    _ = stopped(self.stop.clone()) => (city, ip, None),
+                }
+            })
+            .buffer_unordered(concurrency);
+
+        let mut replies = vec![];
+        let mut complete = true;
+
+        while let Some((city, ip, replies_of_server)) = results.next().await
+        {
+            let Some(server_replies) = replies_of_server else {
+                complete = false;
+                continue;
+            };
+
+            if opts.verbosity == Verbosity::Verbose
+            {
+                match server_replies.samples.iter().cloned().reduce(f64::min)
+                {
+                    Some(min_rtt) => { multi.suspend(|| info!("{} {} {} {:.2} ms", cc, city, ip, min_rtt)); }
+                    None => { multi.suspend(|| info!("{} {} {} unreachable", cc, city, ip)); }
+                }
+            }
+
+            if !opts.dedupe { replies.push((city, ip, server_replies)); }
+
+            country_bar.inc(1);
+            self.total_bar.inc(1);
+        }
+
+        country_bar.finish_and_clear();
+        multi.remove(&country_bar);
+
+        if opts.dedupe
+        {
+            // fan the replies out to every city listing the IP
+            let pinged = self.pinged.lock().unwrap();
+
+            replies = cities.iter()
+                .filter_map(|(city, ip)| pinged.get(ip)?.get().map(|server_replies| (city, ip, server_replies.clone())))
+                .collect();
+        }
+
+        let mut measured = vec![];
+        let mut unreachable = vec![];
+        let mut slow = vec![];
+
+        for (city, ip, Replies { samples, ttl }) in replies
+        {
+            if samples.is_empty()
+            {
+                unreachable.push((city.clone(), ip.clone()));
+                continue;
+            }
+
+            let min_rtt = samples.iter().cloned().fold(f64::MAX, f64::min);
+
+            let measurement = Measurement {
+                city: city.clone(),
+                ip: ip.clone(),
+                rtt: min_rtt,
+                samples,
+                sent: opts.count,
+                ttl,
+                asn: None,
+                location: None,
+                above_threshold: opts.max_acceptable_rtt.is_some_and(|max| min_rtt > max),
+                probe: self.probe.kind(),
+            };
+
+            if measurement.above_threshold && !opts.keep_slow
+            {
+                slow.push(measurement);
+            }
+            else
+            {
+                measured.push(measurement);
+            }
+        }
+
+        CountryScan { cc, measured, unreachable, slow, invalid, complete }
+    }
+}
+
+/// Resolves once `stop` is set, never if it's dropped without being set
+async fn stopped(mut stop: watch::Receiver<bool>)
+{
+    if stop.wait_for(|stopped| *stopped).await.is_err()
+    {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Pings every server and keeps the ones which replied at least once along with a summary of
+/// the ones which didn't, `servers_count` is the total number of servers for progress reporting.
+/// `on_country` gets the results of every country as soon as it's done, also when none of its
+/// servers replied. Setting `stop` cuts the servers in flight short and returns what was collected,
+/// countries left unfinished are not passed to `on_country`.
+///
+/// Every server is measured with `probe`, usually the one [`open_probe`] picks for `opts`.
+pub async fn ping_servers(
+    servers: HashMap<CountryCode, Vec<(City, IP)>>,
+    servers_count: u64,
+    opts: &PingOptions,
+    probe: &dyn Probe,
+    on_country: &mut dyn FnMut(&CountryCode, &[Measurement]),
+    stop: &watch::Receiver<bool>
+) -> (HashMap<CountryCode, Vec<Measurement>>, PingSummary)
+{
+    let mut rtts = HashMap::new();
+    let mut summary = PingSummary::default();
+
+    let in_flight = opts.server_concurrency.values().copied().fold(opts.concurrency, usize::max) * opts.country_concurrency;
+
+    if probe.kind() == ProbeKind::Icmp && in_flight > u16::MAX as usize + 1
+    {
+        warn!("Up to {} pingers may be in flight at once, more than there are ICMP identifiers - replies of the same address may get mixed up", in_flight);
+    }
+
+    let multi = progress::multi(opts.verbosity);
+    let total_bar = multi.add(progress::bar(servers_count, "Total", opts.verbosity));
+
+    let scan = Scan {
+        opts,
+        probe,
+        multi,
+        total_bar,
+        next_ident: AtomicU16::new(rand::random()),
+        resolved: Mutex::new(HashMap::new()),
+        pinged: Mutex::new(HashMap::new()),
+        stop,
+        limiter: opts.pps.map(|pps| RateLimiter::direct(Quota::per_second(pps).allow_burst(NonZeroU32::MIN))),
+    };
+
+    // countries by code unless shuffled, since map order changes from run to run anyway
+    let mut servers: Vec<(CountryCode, Vec<(City, IP)>)> = servers.into_iter().collect();
+    servers.sort_by(|(cc1, _), (cc2, _)| cc1.cmp(cc2));
+
+    if let Some(seed) = opts.shuffle
+    {
+        info!("Shuffling the servers with seed {}", seed);
+
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        for (_, cities) in servers.iter_mut()
+        {
+            cities.shuffle(&mut rng);
+        }
+
+        servers.shuffle(&mut rng);
+    }
+
+    let scan = &scan;
+    let mut countries = stream::iter(servers)
+        .map(|(cc, cities)| scan.ping_country(cc, cities))
+        .buffer_unordered(opts.country_concurrency);
+
+    while let Some(CountryScan { cc, measured, unreachable, slow, invalid, complete }) = countries.next().await
+    {
+        if complete
+        {
+            scan.multi.suspend(|| on_country(&cc, &measured));
+        }
+
+        summary.reachable += measured.len() as u64;
+        summary.attempted.insert(cc.clone(), (measured.len() + unreachable.len() + slow.len() + invalid.len()) as u64);
+        summary.replied.insert(cc.clone(), (measured.len() + slow.len()) as u64);
+        summary.unreachable.extend(unreachable.into_iter().map(|(city, ip)| (cc.clone(), city, ip)));
+        summary.slow.extend(slow.into_iter().map(|server| (cc.clone(), server)));
+        summary.invalid.extend(invalid.into_iter().map(|(city, ip)| (cc.clone(), city, ip)));
+
+        if !measured.is_empty()
+        {
+            rtts.insert(cc, measured);
+        }
+    }
+
+    scan.total_bar.finish();
+
+    (rtts, summary)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::output::{aggregate, ServerMetric};
+
+    fn opts() -> PingOptions
+    {
+        PingOptions {
+            count: 4,
+            warmup: 0,
+            probe_interval: Duration::ZERO,
+            pps: None,
+            payload_bytes: 0,
+            timeout: Duration::from_millis(100),
+            server_budget: None,
+            max_acceptable_rtt: None,
+            keep_slow: false,
+            concurrency: 4,
+            server_concurrency: HashMap::new(),
+            country_concurrency: 1,
+            dedupe: false,
+            shuffle: None,
+            ip_version: IpVersion::Both,
+            exclude: vec![],
+            fallback: None,
+            tcp_port: None,
+            bind: None,
+            verbosity: Verbosity::Quiet,
+        }
+    }
+
+    fn servers(ips: &[&str]) -> HashMap<CountryCode, Vec<(City, IP)>>
+    {
+        let cities = ips.iter().enumerate().map(|(i, ip)| (format!("City {}", i), ip.to_string())).collect();
+
+        HashMap::from([("DE".to_string(), cities)])
+    }
+
+    fn script(replies: &[(&str, Vec<Option<Rtt>>)]) -> ScriptedProbe
+    {
+        ScriptedProbe { replies: replies.iter().map(|(ip, rtts)| (ip.parse().unwrap(), rtts.clone())).collect() }
+    }
+
+    /// Counts the servers it's asked to measure, replying like `script` does
+    struct CountingProbe
+    {
+        script: ScriptedProbe,
+        calls: AtomicU16,
+    }
+
+    #[async_trait]
+    impl Probe for CountingProbe
+    {
+        fn kind(&self) -> ProbeKind
+        {
+            self.script.kind()
+        }
+
+        async fn probe(&self, addr: IpAddr, ident: u16, ctx: &ProbeContext<'_>) -> Replies
+        {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.script.probe(addr, ident, ctx).await
+        }
+    }
+
+    /// Replies with the address itself as the RTT, so a reply handed to the wrong server shows,
+    /// and notes every identifier given out along with any shared by two servers in flight to the same address
+    #[derive(Default)]
+    struct IdentProbe
+    {
+        in_flight: Mutex<HashSet<(u16, IpAddr)>>,
+        idents: Mutex<HashSet<u16>>,
+        collisions: AtomicU16,
+    }
+
+    fn addr_rtt(addr: IpAddr) -> Rtt
+    {
+        match addr
+        {
+            IpAddr::V4(addr) => u32::from(addr) as f64,
+            IpAddr::V6(_) => 0.0,
+        }
+    }
+
+    #[async_trait]
+    impl Probe for IdentProbe
+    {
+        fn kind(&self) -> ProbeKind
+        {
+            ProbeKind::Icmp
+        }
+
+        async fn probe(&self, addr: IpAddr, ident: u16, _ctx: &ProbeContext<'_>) -> Replies
+        {
+            self.idents.lock().unwrap().insert(ident);
+
+            // replies are told apart by identifier and address
+            if !self.in_flight.lock().unwrap().insert((ident, addr)) { self.collisions.fetch_add(1, Ordering::Relaxed); }
+
+            tokio::task::yield_now().await;
+
+            self.in_flight.lock().unwrap().remove(&(ident, addr));
+
+            Replies { samples: vec![addr_rtt(addr)], ttl: None }
+        }
+    }
+
+    async fn run(servers: HashMap<CountryCode, Vec<(City, IP)>>, opts: &PingOptions, probe: &dyn Probe) -> (HashMap<CountryCode, Vec<Measurement>>, PingSummary)
+    {
+        let count = servers.values().map(|cities| cities.len() as u64).sum();
+        let (_stop, stopped) = watch::channel(false);
+
+        ping_servers(servers, count, opts, probe, &mut |_, _| {}, &stopped).await
+    }
+
+    #[test]
+    fn parses_ipv4_and_ipv6_literals_only()
+    {
+        assert_eq!(parse_ip("192.0.2.1"), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+        assert_eq!(parse_ip("2001:db8::1"), Some(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))));
+        assert_eq!(parse_ip("::ffff:192.0.2.1"), Some(IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped())));
+
+        for garbage in ["", "1.2.3", "256.0.0.1", "192.0.2.1:53", "2001:db8::g", "dns.example", " 192.0.2.1"]
+        {
+            assert_eq!(parse_ip(garbage), None, "{:?} was parsed", garbage);
+        }
+    }
+
+    #[tokio::test]
+    async fn numeric_names_are_invalid_rather_than_resolved()
+    {
+        let resolved = Mutex::new(HashMap::new());
+        let multi = progress::multi(Verbosity::Quiet);
+
+        for name in ["12.3", "1.2.3.4.5", "300"]
+        {
+            assert_eq!(resolve_host(name, &resolved, &opts(), &multi).await, None, "{} was resolved", name);
+        }
+
+        assert_eq!(resolved.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn stopped_waits_for_the_stop()
+    {
+        let (stop, receiver) = watch::channel(false);
+
+        assert!(timeout(Duration::from_millis(20), stopped(receiver.clone())).await.is_err());
+
+        stop.send_replace(true);
+        assert!(timeout(Duration::from_millis(20), stopped(receiver.clone())).await.is_ok());
+
+        // a sender gone without stopping never stops the scan
+        let (stop, receiver) = watch::channel(false);
+        drop(stop);
+
+        assert!(timeout(Duration::from_millis(20), stopped(receiver)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn measures_min_median_and_loss()
+    {
+        let probe = script(&[
+            ("10.0.0.1", vec![Some(10.0), None, Some(20.0), Some(30.0)]),
+            ("10.0.0.2", vec![Some(40.0), Some(50.0), Some(60.0), Some(70.0)]),
+            ("10.0.0.3", vec![Some(80.0), Some(90.0), None, None]),
+        ]);
+
+        let (rtts, summary) = run(servers(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]), &opts(), &probe).await;
+
+        let de = &rtts["DE"];
+        let first = de.iter().find(|server| server.ip == "10.0.0.1").unwrap();
+
+        assert_eq!(first.rtt, 10.0);
+        assert_eq!(first.samples, vec![10.0, 20.0, 30.0]);
+        assert_eq!(first.loss(), 25.0);
+        assert_eq!(summary.reachable, 3);
+
+        let stats = aggregate(&"DE".to_string(), de, 1.0, ServerMetric::Min).unwrap();
+
+        assert_eq!(stats.min, 10.0);
+        assert_eq!(stats.median, 40.0);
+        assert_eq!(stats.max, 80.0);
+        // 3 of 12 requests lost
+        assert_eq!(stats.loss, 25.0);
+    }
+
+    #[tokio::test]
+    async fn leaves_out_replies_to_warmup_requests()
+    {
+        let probe = script(&[("10.0.0.1", vec![Some(1.0), Some(10.0), Some(20.0)])]);
+        let opts = PingOptions { count: 2, warmup: 1, ..opts() };
+
+        let (rtts, _) = run(servers(&["10.0.0.1"]), &opts, &probe).await;
+        let server = &rtts["DE"][0];
+
+        assert_eq!(server.samples, vec![10.0, 20.0]);
+        assert_eq!(server.rtt, 10.0);
+        assert_eq!(server.sent, 2);
+        assert_eq!(server.loss(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn replies_slower_than_the_timeout_are_lost()
+    {
+        let probe = script(&[("10.0.0.1", vec![Some(10.0), Some(150.0), Some(20.0), Some(30.0)])]);
+
+        let (rtts, _) = run(servers(&["10.0.0.1"]), &opts(), &probe).await;
+
+        assert_eq!(rtts["DE"][0].samples, vec![10.0, 20.0, 30.0]);
+    }
+
+    #[tokio::test]
+    async fn servers_which_never_reply_are_unreachable()
+    {
+        let probe = script(&[("10.0.0.1", vec![Some(10.0); 4]), ("10.0.0.2", vec![None; 4])]);
+
+        let (rtts, summary) = run(servers(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]), &opts(), &probe).await;
+
+        assert_eq!(rtts["DE"].len(), 1);
+        assert_eq!(summary.reachable, 1);
+
+        let mut unreachable: Vec<&str> = summary.unreachable.iter().map(|(_, _, ip)| ip.as_str()).collect();
+        unreachable.sort();
+
+        assert_eq!(unreachable, vec!["10.0.0.2", "10.0.0.3"]);
+        assert!((summary.reachability(&"DE".to_string()).unwrap() - 100.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn servers_over_the_max_acceptable_rtt_are_left_out()
+    {
+        let probe = script(&[("10.0.0.1", vec![Some(10.0); 4]), ("10.0.0.2", vec![Some(50.0); 4])]);
+        let opts = PingOptions { max_acceptable_rtt: Some(40.0), ..opts() };
+
+        let (rtts, summary) = run(servers(&["10.0.0.1", "10.0.0.2"]), &opts, &probe).await;
+
+        assert_eq!(rtts["DE"].len(), 1);
+        assert_eq!(rtts["DE"][0].ip, "10.0.0.1");
+        assert!(!rtts["DE"][0].above_threshold);
+        assert_eq!(summary.slow.len(), 1);
+        assert_eq!(summary.slow[0].1.ip, "10.0.0.2");
+        assert_eq!(summary.reachability(&"DE".to_string()), Some(100.0));
+    }
+
+    #[tokio::test]
+    async fn keep_slow_flags_them_instead()
+    {
+        let probe = script(&[("10.0.0.1", vec![Some(10.0); 4]), ("10.0.0.2", vec![Some(50.0); 4])]);
+        let opts = PingOptions { max_acceptable_rtt: Some(40.0), keep_slow: true, ..opts() };
+
+        let (rtts, summary) = run(servers(&["10.0.0.1", "10.0.0.2"]), &opts, &probe).await;
+
+        let slow = rtts["DE"].iter().find(|server| server.ip == "10.0.0.2").unwrap();
+
+        assert_eq!(rtts["DE"].len(), 2);
+        assert!(slow.above_threshold);
+        assert!(summary.slow.is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_budget_cuts_the_requests_short()
+    {
+        let probe = script(&[("10.0.0.1", vec![Some(1.0); 10])]);
+        let opts = PingOptions {
+            count: 10,
+            probe_interval: Duration::from_millis(40),
+            server_budget: Some(Duration::from_millis(100)),
+            ..opts()
+        };
+
+        let (rtts, _) = run(servers(&["10.0.0.1"]), &opts, &probe).await;
+        let server = &rtts["DE"][0];
+
+        // requests at 0, 40 and 80 ms fit, the one at 120 ms doesn't
+        assert!(server.received() >= 1 && server.received() < 10, "{} replies", server.received());
+        assert_eq!(server.sent, 10);
+        assert!(server.loss() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn dedupe_probes_every_ip_once()
+    {
+        let probe = CountingProbe { script: script(&[("10.0.0.1", vec![Some(5.0); 4])]), calls: AtomicU16::new(0) };
+        let opts = PingOptions { dedupe: true, country_concurrency: 2, ..opts() };
+
+        let mut servers = servers(&["10.0.0.1", "10.0.0.1", "10.0.0.1"]);
+        servers.insert("PL".to_string(), vec![("Warsaw".to_string(), "10.0.0.1".to_string())]);
+
+        let (rtts, summary) = run(servers, &opts, &probe).await;
+
+        // every entry still gets the result
+        assert_eq!(probe.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(rtts["DE"].len(), 3);
+        assert_eq!(rtts["PL"].len(), 1);
+        assert!(rtts.values().flatten().all(|server| server.rtt == 5.0));
+        assert_eq!(summary.reachable, 4);
+    }
+
+    #[tokio::test]
+    async fn stop_cuts_the_servers_in_flight_short()
+    {
+        let ips: Vec<String> = (1..=10).map(|i| format!("10.0.0.{}", i)).collect();
+        let ips: Vec<&str> = ips.iter().map(String::as_str).collect();
+        let replies: Vec<_> = ips.iter().map(|ip| (*ip, vec![Some(1.0); 3])).collect();
+        let probe = script(&replies);
+        let opts = PingOptions { count: 3, pps: NonZeroU32::new(2), ..opts() };
+
+        let (stop, stopped) = watch::channel(false);
+        let mut finished = vec![];
+        let mut on_country = |cc: &CountryCode, _: &[Measurement]| finished.push(cc.clone());
+        let started = Instant::now();
+
+        let ((rtts, _), _) = tokio::join!(
+            ping_servers(servers(&ips), 10, &opts, &probe, &mut on_country, &stopped),
+            async {
+                sleep(Duration::from_millis(200)).await;
+                stop.send_replace(true);
+            }
+        );
+
+        // waiting on --pps alone would take 15 s
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+        assert!(finished.is_empty());
+        assert!(rtts.get("DE").is_none_or(|measured| measured.len() < 10));
+    }
+
+    #[tokio::test]
+    async fn identifiers_wrap_around_without_mixing_up_replies()
+    {
+        const SERVERS: u32 = 70_000;
+
+        let cities = (0..SERVERS).map(|i| (format!("City {}", i), Ipv4Addr::from(0x0a00_0000 + i).to_string())).collect();
+        let servers = HashMap::from([("DE".to_string(), cities)]);
+        let opts = PingOptions { count: 1, concurrency: 256, ..opts() };
+
+        let probe = IdentProbe::default();
+
+        let (rtts, summary) = run(servers, &opts, &probe).await;
+
+        assert_eq!(probe.idents.lock().unwrap().len(), u16::MAX as usize + 1);
+        assert_eq!(probe.collisions.load(Ordering::Relaxed), 0);
+        assert_eq!(summary.reachable, SERVERS as u64);
+
+        for server in rtts["DE"].iter()
+        {
+            assert_eq!(server.rtt, addr_rtt(server.ip.parse().unwrap()), "{} got another server's reply", server.ip);
+        }
+    }
+}
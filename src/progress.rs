@@ -0,0 +1,34 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verbosity
+{
+    Quiet,// no progress bars, for CI logs
+    Normal,
+    Verbose,// progress bars plus a line for every server
+}
+
+pub fn multi(verbosity: Verbosity) -> MultiProgress
+{
+    match verbosity
+    {
+        Verbosity::Quiet => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        _ => MultiProgress::new(),
+    }
+}
+
+pub fn bar(len: u64, prefix: &str, verbosity: Verbosity) -> ProgressBar
+{
+    if verbosity == Verbosity::Quiet { return ProgressBar::hidden(); }
+
+    let bar = ProgressBar::new(len)
+        .with_prefix(prefix.to_string());
+
+    bar.set_style(
+        ProgressStyle::with_template("{prefix:>8} [{bar:40}] {pos}/{len} {percent:>3}% {msg}")
+            .unwrap()
+            .progress_chars("=> ")
+    );
+
+    bar
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::{params, Connection};
+use crate::CountryCode;
+use crate::error::GeopingError;
+use crate::ping::Measurement;
+
+/// Appends every server of this run to the `measurements` table, creating it on the first run.
+/// All rows of a run share its timestamp (seconds since the Unix epoch), so runs can be told apart.
+pub fn write_sqlite(path: &Path, rtts: &HashMap<CountryCode, Vec<Measurement>>) -> Result<(), GeopingError>
+{
+    insert_measurements(path, rtts).map_err(|source| GeopingError::Sqlite { path: path.to_path_buf(), source })
+}
+
+fn insert_measurements(path: &Path, rtts: &HashMap<CountryCode, Vec<Measurement>>) -> rusqlite::Result<()>
+{
+    let mut conn = Connection::open(path)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS measurements (
+            timestamp INTEGER NOT NULL,
+            country TEXT NOT NULL,
+            city TEXT NOT NULL,
+            ip TEXT NOT NULL,
+            min_rtt REAL NOT NULL,
+            loss REAL NOT NULL
+        )",
+        []
+    )?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default() as i64;
+
+    let tx = conn.transaction()?;
+
+    {
+        let mut insert = tx.prepare("INSERT INTO measurements (timestamp, country, city, ip, min_rtt, loss) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+
+        for (cc, servers) in rtts
+        {
+            for server in servers
+            {
+                insert.execute(params![timestamp, cc, server.city, server.ip, server.rtt, server.loss()])?;
+            }
+        }
+    }
+
+    tx.commit()
+}